@@ -0,0 +1,288 @@
+use std::io;
+use std::time::Instant;
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use tiki_proto::packet::ReceivedPacket;
+use tiki_proto::serverbound::Serverbound;
+use tiki_proto::{
+    ClientConfig, ClientConnectionState, Credentials, DisconnectReason, Event, Input, Output,
+};
+
+const RECV_BUFFER_SIZE: usize = 1536;
+
+/// Something the connection task hands to the game.
+#[derive(Debug)]
+pub enum Received {
+    Packet(ReceivedPacket),
+    Event(Event),
+    /// The connection is closed, and nothing else will be received.
+    Disconnected(DisconnectReason),
+}
+
+/// Connection to a server, driven by a task on the tokio runtime.
+///
+/// Unlike [`Connection`](crate::connection::Connection), this never blocks
+/// the caller: packets are exchanged with the task over channels, and the
+/// task sleeps until data arrives or the state machine's next deadline.
+pub struct AsyncConnection {
+    outgoing: mpsc::UnboundedSender<Serverbound>,
+    incoming: mpsc::UnboundedReceiver<Received>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl AsyncConnection {
+    /// Starts connecting to a server.
+    ///
+    /// Must be called from within a tokio runtime, which the connection task is spawned on.
+    pub async fn connect(
+        address: impl ToSocketAddrs,
+        credentials: Credentials,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(address).await?;
+
+        let state = ClientConnectionState::with_config(credentials, config);
+
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(drive(socket, state, outgoing_rx, incoming_tx));
+
+        Ok(Self {
+            outgoing: outgoing_tx,
+            incoming: incoming_rx,
+            task,
+        })
+    }
+
+    /// Queues a packet to be sent to the server.
+    ///
+    /// Packets sent after the connection is closed are dropped, and so are
+    /// packets which can't be encoded, e.g. because a string in them is too long.
+    pub fn send_packet(&self, packet: impl Into<Serverbound>) {
+        let _ = self.outgoing.send(packet.into());
+    }
+
+    /// Returns the next thing received, without waiting.
+    ///
+    /// Meant to be called from the game loop until it returns `None`.
+    pub fn try_recv(&mut self) -> Option<Received> {
+        self.incoming.try_recv().ok()
+    }
+
+    /// Waits for the next thing to be received.
+    ///
+    /// Returns `None` once the connection task has finished and everything
+    /// it sent has been received.
+    pub async fn recv(&mut self) -> Option<Received> {
+        self.incoming.recv().await
+    }
+
+    /// Waits for the connection task to finish, returning the socket error it failed with, if any.
+    pub async fn join(self) -> io::Result<()> {
+        drop(self.outgoing);
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+async fn drive(
+    socket: UdpSocket,
+    mut state: ClientConnectionState,
+    mut outgoing: mpsc::UnboundedReceiver<Serverbound>,
+    incoming: mpsc::UnboundedSender<Received>,
+) -> io::Result<()> {
+    let mut buf = [0; RECV_BUFFER_SIZE];
+
+    loop {
+        loop {
+            match state.poll_output(Instant::now()) {
+                Output::SendData(data) => match socket.send(&data).await {
+                    Ok(_) => {}
+                    Err(e) if is_unreachable(&e) => tracing::debug!("server port unreachable"),
+                    Err(e) => return Err(e),
+                },
+                Output::Disconnect(reason) => {
+                    forward(&mut state, &incoming);
+                    let _ = incoming.send(Received::Disconnected(reason));
+                    return Ok(());
+                }
+                Output::Wait => break,
+            }
+        }
+
+        if !forward(&mut state, &incoming) {
+            // Nobody is listening anymore.
+            return Ok(());
+        }
+
+        let deadline = state.next_timeout().map(tokio::time::Instant::from_std);
+
+        tokio::select! {
+            result = socket.recv(&mut buf) => {
+                let len = match result {
+                    Ok(len) => len,
+                    Err(e) if is_unreachable(&e) => {
+                        tracing::debug!("server port unreachable");
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                // A malformed datagram doesn't mean the connection is broken,
+                // e.g. it may not even come from the server.
+                let result = state.submit_input(Input::ReceivedData(&buf[..len]), Instant::now());
+                if let Err(e) = result {
+                    tracing::debug!(error = %e, len, "ignoring malformed datagram");
+                }
+            }
+            packet = outgoing.recv() => match packet {
+                Some(packet) => {
+                    if let Err(e) = state.send_packet(packet) {
+                        tracing::warn!(error = %e, "dropping packet which can't be sent");
+                    }
+                }
+                None => return Ok(()),
+            },
+            _ = sleep_until(deadline) => {
+                let _ = state.submit_input(Input::TimedOut, Instant::now());
+            }
+        }
+    }
+}
+
+/// Returns whether an error is an ICMP port unreachable, reported on the next send or receive.
+///
+/// The server may just be restarting, so this isn't fatal: if it's gone for
+/// good, the connection times out like when packets get lost.
+fn is_unreachable(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::ConnectionRefused
+}
+
+/// Hands received packets and events to the game.
+///
+/// Returns whether the game is still receiving.
+fn forward(state: &mut ClientConnectionState, incoming: &mpsc::UnboundedSender<Received>) -> bool {
+    for packet in state.recv_packets() {
+        if incoming.send(Received::Packet(packet)).is_err() {
+            return false;
+        }
+    }
+
+    while let Some(event) = state.poll_event() {
+        if incoming.send(Received::Event(event)).is_err() {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Sleeps until the deadline, or forever if there is none.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A server which never answers.
+    async fn silent_server() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            name: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+        }
+    }
+
+    async fn disconnect_reason(connection: &mut AsyncConnection) -> DisconnectReason {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match connection.recv().await {
+                    Some(Received::Disconnected(reason)) => break reason,
+                    Some(_) => {}
+                    None => panic!("connection closed without a reason"),
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn times_out_when_the_server_is_silent() {
+        let server = silent_server().await;
+        let config = ClientConfig {
+            timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        let mut connection =
+            AsyncConnection::connect(server.local_addr().unwrap(), credentials(), config)
+                .await
+                .unwrap();
+
+        // Nothing but the deadlines wakes the task up.
+        assert_eq!(
+            disconnect_reason(&mut connection).await,
+            DisconnectReason::TimedOut
+        );
+        connection.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn times_out_when_nothing_listens() {
+        let address = silent_server().await.local_addr().unwrap();
+        let config = ClientConfig {
+            timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        let mut connection = AsyncConnection::connect(address, credentials(), config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            disconnect_reason(&mut connection).await,
+            DisconnectReason::TimedOut
+        );
+        connection.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_when_dropped() {
+        let server = silent_server().await;
+        let connection = AsyncConnection::connect(
+            server.local_addr().unwrap(),
+            credentials(),
+            ClientConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let metrics = tokio::runtime::Handle::current().metrics();
+        assert_eq!(metrics.num_alive_tasks(), 1);
+
+        drop(connection);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.num_alive_tasks() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -1,11 +1,11 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use std::io::ErrorKind;
 use std::net::{ToSocketAddrs, UdpSocket};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use tiki_proto::{ClientConnectionState, Credentials, Input, Output};
 
-const MAX_FRAME_SIZE: usize = 1536;
+const RECV_BUFFER_SIZE: usize = 1536;
 
 pub struct Connection {
     socket: UdpSocket,
@@ -28,32 +28,53 @@ impl Connection {
     }
 
     pub fn poll(&mut self) -> Result<()> {
-        let mut buf = [0; MAX_FRAME_SIZE];
+        let mut buf = [0; RECV_BUFFER_SIZE];
 
-        let output = self.state.poll_output();
+        let output = self.state.poll_output(Instant::now());
 
-        println!("{:?}", output);
+        tracing::trace!(?output, "polled output");
 
         match output {
-            Output::SendData(data) => {
-                self.socket.send(&data).unwrap();
-            }
+            Output::SendData(data) => match self.socket.send(&data) {
+                Ok(_) => {}
+                // Not fatal, like when receiving.
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    tracing::debug!("server port unreachable");
+                }
+                Err(e) => Err(e)?,
+            },
             Output::Wait => {
                 std::thread::sleep(Duration::from_millis(200));
             }
-            Output::Disconnect => {
-                bail!("disconnect");
+            Output::Disconnect(reason) => {
+                // Callers can downcast to `DisconnectReason` to tell the player why.
+                return Err(reason.into());
             }
         }
 
         match self.socket.recv(&mut buf) {
-            Ok(packet_len) => self
-                .state
-                .submit_input(Input::ReceivedData(&buf[..packet_len]))
-                .unwrap(),
+            Ok(len) => {
+                // A malformed datagram doesn't mean the connection is broken,
+                // e.g. it may not even come from the server.
+                let result = self
+                    .state
+                    .submit_input(Input::ReceivedData(&buf[..len]), Instant::now());
+                if let Err(e) = result {
+                    tracing::debug!(error = %e, len, "ignoring malformed datagram");
+                }
+            }
             Err(e) => match e.kind() {
-                ErrorKind::TimedOut | ErrorKind::WouldBlock => {
-                    self.state.submit_input(Input::TimedOut).unwrap();
+                // An ICMP port unreachable is reported on the next call, but the
+                // server may just be restarting: if it's gone for good, the
+                // connection times out like when packets get lost.
+                ErrorKind::ConnectionRefused | ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                    if e.kind() == ErrorKind::ConnectionRefused {
+                        tracing::debug!("server port unreachable");
+                    }
+
+                    self.state
+                        .submit_input(Input::TimedOut, Instant::now())
+                        .unwrap();
                 }
                 _ => Err(e)?,
             },
@@ -2,10 +2,12 @@
 #![allow(clippy::single_match)]
 #![allow(dead_code)]
 
+pub mod async_connection;
 pub mod connection;
 
 use tiki_input::InputHandler;
 use tiki_render::Renderer;
+use tracing_subscriber::EnvFilter;
 use winit::application::ApplicationHandler;
 use winit::event::WindowEvent;
 use winit::event_loop::{ActiveEventLoop, EventLoop};
@@ -74,6 +76,11 @@ impl ApplicationHandler for App {
 }
 
 fn main() {
+    // Filtered with RUST_LOG, e.g. `RUST_LOG=tiki_proto=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
     let event_loop = EventLoop::new().unwrap();
 
     let mut app = App::new();
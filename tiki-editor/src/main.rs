@@ -178,8 +178,8 @@ fn main() {
 
     let mut world = World::open(path).unwrap();
     let block = world.get_block(pos(0, 0, 0)).unwrap();
-    let node = block.get_node(pos(0, 0, 0));
-    let name = block.name(node.id);
+    let node = block.get_node(pos(0, 0, 0)).unwrap();
+    let name = block.name(node.id).unwrap_or("unknown");
     println!("{}", name);
 
     let event_loop = EventLoop::new().unwrap();
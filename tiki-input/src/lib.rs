@@ -15,22 +15,19 @@ impl InputHandler {
     }
 
     pub fn submit_winit_event(&mut self, event: &winit::event::WindowEvent) {
-        match event {
-            WindowEvent::KeyboardInput {  event, .. } => {
-                let PhysicalKey::Code(code) = event.physical_key else {
-                    return;
-                };
+        if let WindowEvent::KeyboardInput { event, .. } = event {
+            let PhysicalKey::Code(code) = event.physical_key else {
+                return;
+            };
 
-                match event.state {
-                    ElementState::Pressed => {
-                        self.pressed_keys.insert(code);
-                    },
-                    ElementState::Released => {
-                        self.pressed_keys.remove(&code);
-                    },
+            match event.state {
+                ElementState::Pressed => {
+                    self.pressed_keys.insert(code);
+                }
+                ElementState::Released => {
+                    self.pressed_keys.remove(&code);
                 }
             }
-            _ => {}
         }
     }
 
@@ -1,51 +1,224 @@
+use std::collections::HashMap;
+
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{Data, DeriveInput, Expr, ItemEnum, Meta, Variant};
+use quote::{format_ident, quote, ToTokens};
+use syn::{
+    parse_quote, Attribute, Data, DataEnum, DataStruct, DeriveInput, Expr, ExprLit, Field, Fields,
+    Generics, ItemEnum, Lifetime, LifetimeParam, Lit, Meta, MetaNameValue, Path, Type, Variant,
+};
+
+/// Attributes which the packet macro handles itself, and removes from the variants.
+const PACKET_ATTRIBUTES: [&str; 3] = ["id", "channel", "reliable"];
 
-fn parse_id(variant: &Variant) -> &Expr {
-    let attribute = variant
+/// Number of channels in the transport protocol, which packets must stay below.
+const CHANNEL_COUNT: u8 = 3;
+
+/// A variant of a packet enum, like `#[id = 0x02] Hello(Hello)`.
+struct PacketVariant<'a> {
+    variant: &'a Variant,
+    id: u16,
+    channel: u8,
+    reliable: bool,
+    ty: &'a Type,
+}
+
+/// Finds the only attribute with a name, if there is one.
+fn find_attr<'a>(variant: &'a Variant, name: &str) -> syn::Result<Option<&'a Attribute>> {
+    let mut attrs = variant
         .attrs
         .iter()
-        .find(|attr| attr.path().is_ident("id"))
-        .unwrap_or_else(|| panic!("packet variant doesn't have an #[id()] attribute"));
+        .filter(|attr| attr.path().is_ident(name));
+
+    let attr = attrs.next();
 
-    match &attribute.meta {
-        Meta::NameValue(name_value) => &name_value.value,
-        _ => panic!("id should be a name-value attribute, like #[id = 0x00]"),
+    if let Some(extra) = attrs.next() {
+        return Err(syn::Error::new_spanned(
+            extra,
+            format!("packet variant has several #[{name}] attributes"),
+        ));
     }
+
+    Ok(attr)
 }
 
-fn make_packet_impls(input: &ItemEnum) -> proc_macro2::TokenStream {
+/// Parses an attribute like `#[id = 0x02]`, if the variant has it.
+fn parse_int_attr<T>(variant: &Variant, name: &str) -> syn::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let Some(attr) = find_attr(variant, name)? else {
+        return Ok(None);
+    };
+
+    let Meta::NameValue(name_value) = &attr.meta else {
+        return Err(syn::Error::new_spanned(
+            attr,
+            format!("{name} should be a name-value attribute, like #[{name} = 2]"),
+        ));
+    };
+
+    parse_int(&name_value.value, name).map(Some)
+}
+
+/// Parses an integer literal, or reports what it should have been.
+fn parse_int<T>(expr: &Expr, what: &str) -> syn::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(int), ..
+        }) => int.base10_parse(),
+        expr => Err(syn::Error::new_spanned(
+            expr,
+            format!("{what} should be an integer literal"),
+        )),
+    }
+}
+
+fn parse_packet_variant(variant: &Variant) -> syn::Result<PacketVariant<'_>> {
+    let Some(id) = parse_int_attr(variant, "id")? else {
+        return Err(syn::Error::new_spanned(
+            variant,
+            "packet variant needs an id, like #[id = 0x02]",
+        ));
+    };
+
+    let channel = parse_int_attr(variant, "channel")?.unwrap_or(0);
+
+    if channel >= CHANNEL_COUNT {
+        let attr = find_attr(variant, "channel")?;
+        return Err(syn::Error::new_spanned(
+            attr,
+            format!("channel should be below {CHANNEL_COUNT}"),
+        ));
+    }
+
+    let reliable = match find_attr(variant, "reliable")? {
+        Some(attr) if !matches!(attr.meta, Meta::Path(_)) => {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "reliable doesn't take a value, write #[reliable]",
+            ))
+        }
+        attr => attr.is_some(),
+    };
+
+    let ty = match &variant.fields {
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => &fields.unnamed[0].ty,
+        fields => {
+            let message = "packet variant should hold a single packet type, like Hello(Hello)";
+
+            // Unit variants have no fields to point at.
+            return Err(match fields {
+                Fields::Unit => syn::Error::new_spanned(&variant.ident, message),
+                fields => syn::Error::new_spanned(fields, message),
+            });
+        }
+    };
+
+    Ok(PacketVariant {
+        variant,
+        id,
+        channel,
+        reliable,
+        ty,
+    })
+}
+
+/// Parses the variants of a packet enum, making sure every id is only used once.
+///
+/// Returns the valid variants along with the errors for the others, so the
+/// impls for the valid ones don't cause errors of their own.
+fn parse_packet_variants(input: &ItemEnum) -> (Vec<PacketVariant<'_>>, Option<syn::Error>) {
+    let mut variants = Vec::new();
+    let mut errors: Option<syn::Error> = None;
+    let mut seen = HashMap::new();
+
+    for variant in &input.variants {
+        let result = parse_packet_variant(variant).and_then(|parsed| {
+            if let Some(other) = seen.insert(parsed.id, &variant.ident) {
+                return Err(syn::Error::new_spanned(
+                    &variant.ident,
+                    format!("packet id 0x{:02X} is already used by {other}", parsed.id),
+                ));
+            }
+
+            Ok(parsed)
+        });
+
+        // Report every bad variant at once, rather than one per build.
+        match result {
+            Ok(parsed) => variants.push(parsed),
+            Err(e) => match &mut errors {
+                Some(errors) => errors.combine(e),
+                None => errors = Some(e),
+            },
+        }
+    }
+
+    (variants, errors)
+}
+
+fn make_packet_impls(
+    input: &ItemEnum,
+    variants: &[PacketVariant],
+    has_errors: bool,
+) -> proc_macro2::TokenStream {
     let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let (borrow_generics, lifetime) = borrow_generics(&input.generics);
+    let (borrow_impl_generics, _, borrow_where_clause) = borrow_generics.split_for_impl();
 
-    let serialize_variants = input.variants.iter().map(|variant| {
+    // The invalid variants have been reported already.
+    let unreachable = has_errors.then(|| quote! { _ => ::core::unreachable!(), });
+
+    let serialize_variants = variants.iter().map(|PacketVariant { variant, id, .. }| {
         let v_ident = &variant.ident;
-        let id = parse_id(variant);
 
         quote! {
             #ident::#v_ident(packet) => {
-                (#id as u16).serialize(w);
-                packet.serialize(w);
+                ::tiki_proto::serialize::Serialize::serialize(&#id, w, ctx)?;
+                ::tiki_proto::serialize::Serialize::serialize(packet, w, ctx)
             }
         }
     });
 
-    let deserialize_variants = input.variants.iter().map(|variant| {
-        let v_ident = &variant.ident;
-        let id = parse_id(variant);
-        let ty = variant.fields.iter().next().unwrap();
+    let deserialize_variants = variants.iter().map(
+        |PacketVariant {
+             variant, id, ty, ..
+         }| {
+            let v_ident = &variant.ident;
 
-        quote! {
-             #id => Ok(#ident::#v_ident(#ty::deserialize(r)?)),
-        }
-    });
+            quote! {
+                #id => ::core::result::Result::Ok(#ident::#v_ident(
+                    <#ty as ::tiki_proto::serialize::Serialize>::deserialize(r, ctx)?
+                )),
+            }
+        },
+    );
+
+    let borrowed_variants = variants.iter().map(
+        |PacketVariant {
+             variant, id, ty, ..
+         }| {
+            let v_ident = &variant.ident;
 
-    let from_impls = input.variants.iter().map(|variant| {
+            quote! {
+                #id => ::core::result::Result::Ok(#ident::#v_ident(
+                    <#ty as ::tiki_proto::serialize::Deserialize<'_>>::deserialize_borrowed(r, ctx)?
+                )),
+            }
+        },
+    );
+
+    let from_impls = variants.iter().map(|PacketVariant { variant, ty, .. }| {
         let v_ident = &variant.ident;
-        let ty = variant.fields.iter().next().unwrap();
 
         quote! {
-            impl From<#ty> for #ident {
+            impl #impl_generics ::core::convert::From<#ty> for #ident #ty_generics #where_clause {
                 fn from(v: #ty) -> Self {
                     #ident::#v_ident(v)
                 }
@@ -54,18 +227,39 @@ fn make_packet_impls(input: &ItemEnum) -> proc_macro2::TokenStream {
     });
 
     quote! {
-        impl crate::serialize::Serialize for #ident {
-            fn serialize<W: std::io::Write>(&self, w: &mut W) {
+        impl #impl_generics ::tiki_proto::serialize::Serialize for #ident #ty_generics #where_clause {
+            fn serialize<W: ::std::io::Write>(
+                &self,
+                w: &mut W,
+                ctx: &::tiki_proto::serialize::Context,
+            ) -> ::core::result::Result<(), ::tiki_proto::Error> {
                 match self {
                     #(#serialize_variants),*
+                    #unreachable
                 }
             }
 
-            fn deserialize<R: std::io::Read>(r: &mut R) -> Result<Self, crate::Error> {
-                let id = u16::deserialize(r)?;
+            fn deserialize<R: ::std::io::Read>(
+                r: &mut R,
+                ctx: &::tiki_proto::serialize::Context,
+            ) -> ::core::result::Result<Self, ::tiki_proto::Error> {
+                let id = <u16 as ::tiki_proto::serialize::Serialize>::deserialize(r, ctx)?;
                 match id {
                     #(#deserialize_variants)*
-                    _ => Err(Error::UnknownPacket(id))
+                    _ => ::core::result::Result::Err(::tiki_proto::Error::UnknownPacket(id)),
+                }
+            }
+        }
+
+        impl #borrow_impl_generics ::tiki_proto::serialize::Deserialize<#lifetime> for #ident #ty_generics #borrow_where_clause {
+            fn deserialize_borrowed(
+                r: &mut &#lifetime [u8],
+                ctx: &::tiki_proto::serialize::Context,
+            ) -> ::core::result::Result<Self, ::tiki_proto::Error> {
+                let id = <u16 as ::tiki_proto::serialize::Serialize>::deserialize(r, ctx)?;
+                match id {
+                    #(#borrowed_variants)*
+                    _ => ::core::result::Result::Err(::tiki_proto::Error::UnknownPacket(id)),
                 }
             }
         }
@@ -74,76 +268,685 @@ fn make_packet_impls(input: &ItemEnum) -> proc_macro2::TokenStream {
     }
 }
 
+/// Generates the table of packet types, and methods to look packets up in it.
+fn make_packet_info(
+    input: &ItemEnum,
+    variants: &[PacketVariant],
+    has_errors: bool,
+) -> proc_macro2::TokenStream {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let unreachable = has_errors.then(|| quote! { _ => ::core::unreachable!(), });
+
+    let table = variants.iter().map(
+        |PacketVariant {
+             variant,
+             id,
+             channel,
+             reliable,
+             ..
+         }| {
+            let name = variant.ident.to_string();
+
+            quote! {
+                ::tiki_proto::packet::PacketInfo {
+                    id: #id,
+                    name: #name,
+                    channel: #channel,
+                    reliable: #reliable,
+                }
+            }
+        },
+    );
+
+    let info_variants = variants
+        .iter()
+        .enumerate()
+        .map(|(i, PacketVariant { variant, .. })| {
+            let v_ident = &variant.ident;
+            quote! { #ident::#v_ident(_) => &Self::PACKETS[#i], }
+        });
+
+    let by_id_variants = variants
+        .iter()
+        .enumerate()
+        .map(|(i, PacketVariant { id, .. })| {
+            quote! { #id => ::core::option::Option::Some(&Self::PACKETS[#i]), }
+        });
+
+    quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            /// Every packet type, in the order they're declared.
+            pub const PACKETS: &'static [::tiki_proto::packet::PacketInfo] = &[#(#table),*];
+
+            /// Returns what's known about the type of this packet.
+            pub fn info(&self) -> &'static ::tiki_proto::packet::PacketInfo {
+                match self {
+                    #(#info_variants)*
+                    #unreachable
+                }
+            }
+
+            /// Looks up a packet type by the id it's sent with.
+            pub fn info_by_id(id: u16) -> ::core::option::Option<&'static ::tiki_proto::packet::PacketInfo> {
+                match id {
+                    #(#by_id_variants)*
+                    _ => ::core::option::Option::None,
+                }
+            }
+
+            pub fn id(&self) -> u16 {
+                self.info().id
+            }
+
+            pub fn name(&self) -> &'static str {
+                self.info().name
+            }
+
+            /// Returns the channel the packet is sent on.
+            pub fn channel(&self) -> u8 {
+                self.info().channel
+            }
+
+            /// Returns whether the packet is sent reliably.
+            pub fn is_reliable(&self) -> bool {
+                self.info().reliable
+            }
+        }
+    }
+}
+
+/// Rebuilds the enum without the packet attributes, which aren't real attributes.
 fn make_packet_enum(input: &ItemEnum) -> proc_macro2::TokenStream {
     let variants = input.variants.iter().map(|variant| {
+        let attrs = variant.attrs.iter().filter(|attr| {
+            !PACKET_ATTRIBUTES
+                .iter()
+                .any(|name| attr.path().is_ident(name))
+        });
         let ident = &variant.ident;
         let fields = &variant.fields;
-        quote! { #ident #fields }
+        quote! { #(#attrs)* #ident #fields }
     });
 
     let attrs = &input.attrs;
     let vis = &input.vis;
     let ident = &input.ident;
+    let generics = &input.generics;
+    let where_clause = &generics.where_clause;
 
-    quote! { #(#attrs)* #vis enum #ident { #(#variants),* } }
+    quote! { #(#attrs)* #vis enum #ident #generics #where_clause { #(#variants),* } }
 }
 
+/// Turns an enum of packets into the packet type for one direction.
+///
+/// Every variant holds a single packet type and has an `#[id = ...]`, which
+/// is written before the packet. The ids must be unique.
+///
+/// Packets are sent on the channel given with `#[channel = ...]`, or 0 by
+/// default, and reliably if the variant is marked `#[reliable]`. This is
+/// available from the generated `channel()` and `is_reliable()` methods,
+/// along with a table of all packet types in `PACKETS`.
+///
+/// Besides `Serialize`, the enum implements `Deserialize`, so packets holding
+/// borrowed data can be read without copying it. Such packets make the enum
+/// generic over their lifetime, like `Clientbound<'a>`.
 #[proc_macro_attribute]
-pub fn packet(_args: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+pub fn packet(
+    _args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
     let input = syn::parse_macro_input!(input as ItemEnum);
     let packet_enum = make_packet_enum(&input);
-    let serialize_impl = make_packet_impls(&input);
+
+    let (variants, errors) = parse_packet_variants(&input);
+    let serialize_impl = make_packet_impls(&input, &variants, errors.is_some());
+    let info_impl = make_packet_info(&input, &variants, errors.is_some());
+    let errors = errors.map(syn::Error::into_compile_error);
+
     let tokens = quote! {
         #packet_enum
         #serialize_impl
+        #info_impl
+        #errors
     };
 
     tokens.into()
 }
 
-#[proc_macro_derive(Serialize)]
-pub fn derive_serialize(input: TokenStream) -> TokenStream {
-    let input = syn::parse_macro_input!(input as DeriveInput);
+/// Options given to a field with `#[serialize(...)]`.
+#[derive(Default)]
+struct FieldOptions {
+    /// Integer type to prefix the field's length with, instead of its own encoding.
+    len: Option<Type>,
+    /// Module with `serialize` and `deserialize` functions to use instead of the field's impl.
+    with: Option<Path>,
+    /// Protocol version the field was added in. Older peers neither send nor expect it.
+    since: Option<Expr>,
+    /// Whether older peers may leave the field out at the end of the packet.
+    trailing: bool,
+}
 
-    let ident = input.ident;
-    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+fn parse_field_options(field: &Field) -> syn::Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+
+    let attrs = field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serialize"));
+
+    for attr in attrs {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("len") {
+                options.len = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("with") {
+                options.with = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("since") {
+                options.since = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("trailing") {
+                options.trailing = true;
+            } else {
+                return Err(meta.error("expected `len`, `with`, `since` or `trailing`"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    if let (Some(len), Some(_)) = (&options.len, &options.with) {
+        return Err(syn::Error::new_spanned(
+            len,
+            "`len` can't be combined with `with`",
+        ));
+    }
+
+    Ok(options)
+}
+
+/// Code generated for a list of fields.
+struct FieldsCode {
+    /// Statements writing each field.
+    serialize: Vec<proc_macro2::TokenStream>,
+    /// The fields to construct the value with when reading, like `{ a: ..., b: ... }`.
+    deserialize: proc_macro2::TokenStream,
+    /// Like `deserialize`, but borrowing from the packet with `Deserialize`.
+    deserialize_borrowed: proc_macro2::TokenStream,
+}
 
-    let data = if let Data::Struct(data) = input.data {
-        data
-    } else {
-        panic!("#[derive(Serialize)] only works with structs");
+/// Generates the code to serialize and deserialize a list of fields.
+///
+/// `access` returns an expression for a reference to each field.
+fn make_fields(
+    fields: &Fields,
+    access: impl Fn(usize, &Field) -> proc_macro2::TokenStream,
+) -> syn::Result<FieldsCode> {
+    let mut serialize_fields = Vec::new();
+    let mut deserialize_fields = Vec::new();
+    let mut borrowed_fields = Vec::new();
+    let mut after_trailing = false;
+
+    for (i, field) in fields.iter().enumerate() {
+        let ty = &field.ty;
+        let value = access(i, field);
+        let options = parse_field_options(field)?;
+
+        // Only the end of a packet can be missing.
+        if after_trailing && !options.trailing {
+            return Err(syn::Error::new_spanned(
+                field,
+                "fields after a trailing field must be trailing too",
+            ));
+        }
+
+        after_trailing |= options.trailing;
+
+        let serialize = if let Some(len) = &options.len {
+            quote! { ::tiki_proto::serialize::serialize_with_len::<#len, _, _>(#value, w, ctx) }
+        } else if let Some(with) = &options.with {
+            quote! { #with::serialize(#value, w, ctx) }
+        } else {
+            quote! { ::tiki_proto::serialize::Serialize::serialize(#value, w, ctx) }
+        };
+
+        let serialize = match &options.since {
+            Some(since) => quote! {
+                if ctx.protocol_version >= #since {
+                    #serialize?;
+                }
+            },
+            None => quote! { #serialize?; },
+        };
+
+        let deserialize = |borrowed: bool| {
+            let read = match (&options.len, &options.with) {
+                (Some(len), _) if borrowed => quote! {
+                    ::tiki_proto::serialize::deserialize_borrowed_with_len::<#len, #ty>(r, ctx)
+                },
+                (Some(len), _) => quote! {
+                    ::tiki_proto::serialize::deserialize_with_len::<#len, #ty, _>(r, ctx)
+                },
+                (None, Some(with)) => quote! { #with::deserialize(r, ctx) },
+                (None, None) if borrowed => quote! {
+                    <#ty as ::tiki_proto::serialize::Deserialize<'_>>::deserialize_borrowed(r, ctx)
+                },
+                (None, None) => quote! {
+                    <#ty as ::tiki_proto::serialize::Serialize>::deserialize(r, ctx)
+                },
+            };
+
+            let read = if options.trailing {
+                let trailing = if borrowed {
+                    quote! { ::tiki_proto::serialize::trailing_borrowed }
+                } else {
+                    quote! { ::tiki_proto::serialize::trailing }
+                };
+                quote! {
+                    ::core::option::Option::unwrap_or_default(#trailing(r, |r| #read)?)
+                }
+            } else {
+                quote! { #read? }
+            };
+
+            let read = match &options.since {
+                Some(since) => quote! {
+                    if ctx.protocol_version >= #since {
+                        #read
+                    } else {
+                        ::core::default::Default::default()
+                    }
+                },
+                None => read,
+            };
+
+            match &field.ident {
+                Some(ident) => quote! { #ident: #read },
+                None => read,
+            }
+        };
+
+        serialize_fields.push(serialize);
+        deserialize_fields.push(deserialize(false));
+        borrowed_fields.push(deserialize(true));
+    }
+
+    let constructor = |values: Vec<proc_macro2::TokenStream>| match fields {
+        Fields::Named(_) => quote! { { #(#values),* } },
+        Fields::Unnamed(_) => quote! { ( #(#values),* ) },
+        Fields::Unit => quote! {},
     };
 
-    let serialize_fields = data.fields.iter().map(|field| {
-        let ident = &field.ident;
+    Ok(FieldsCode {
+        serialize: serialize_fields,
+        deserialize: constructor(deserialize_fields),
+        deserialize_borrowed: constructor(borrowed_fields),
+    })
+}
 
-        quote! {
-            self.#ident.serialize(w);
+/// Names the fields of an enum variant are bound to when matching on it.
+fn field_bindings(fields: &Fields) -> (Vec<syn::Ident>, proc_macro2::TokenStream) {
+    let bindings: Vec<_> = (0..fields.len())
+        .map(|i| format_ident!("field{i}"))
+        .collect();
+
+    let pattern = match fields {
+        Fields::Named(fields) => {
+            let idents = fields.named.iter().map(|field| &field.ident);
+            quote! { { #(#idents: #bindings),* } }
         }
-    });
+        Fields::Unnamed(_) => quote! { ( #(#bindings),* ) },
+        Fields::Unit => quote! {},
+    };
+
+    (bindings, pattern)
+}
+
+/// Integer types which can be used as tags, since they convert to `u64`.
+const TAG_TYPES: [&str; 4] = ["u8", "u16", "u32", "u64"];
 
-    let deserialize_fields = data.fields.iter().map(|field| {
+/// Finds the type an enum's tag is written as, from `#[tag(u16)]`,
+/// `#[tag = "u16"]` or `#[repr(u8)]`.
+fn parse_tag_type(input: &DeriveInput) -> syn::Result<Type> {
+    if let Some(attr) = input.attrs.iter().find(|attr| attr.path().is_ident("tag")) {
+        return match &attr.meta {
+            Meta::List(list) => list.parse_args(),
+            // Only literals may follow the `=`, so the type has to be quoted.
+            Meta::NameValue(MetaNameValue {
+                value:
+                    Expr::Lit(ExprLit {
+                        lit: Lit::Str(ty), ..
+                    }),
+                ..
+            }) => ty.parse(),
+            _ => Err(syn::Error::new_spanned(
+                attr,
+                "tag should name the tag type, like #[tag(u16)] or #[tag = \"u16\"]",
+            )),
+        };
+    }
+
+    let mut tag = None;
+
+    let reprs = input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("repr"));
+
+    for attr in reprs {
+        attr.parse_nested_meta(|meta| {
+            if TAG_TYPES.iter().any(|ty| meta.path.is_ident(ty)) {
+                tag = Some(Type::Path(syn::TypePath {
+                    qself: None,
+                    path: meta.path,
+                }));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    tag.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "enums need a tag type, like #[repr(u8)] or #[tag(u16)]",
+        )
+    })
+}
+
+/// Returns the type of the tag held by a variant marked as the fallback for
+/// unknown tags, or `None` for other variants.
+fn unknown_variant_tag(variant: &Variant) -> syn::Result<Option<&Type>> {
+    let mut unknown = false;
+
+    for attr in variant
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serialize"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("unknown") {
+                unknown = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `unknown`"))
+            }
+        })?;
+    }
+
+    if !unknown {
+        return Ok(None);
+    }
+
+    match &variant.fields {
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => Ok(Some(&fields.unnamed[0].ty)),
+        _ => Err(syn::Error::new_spanned(
+            variant,
+            "the unknown variant should hold the tag, like Unknown(u8)",
+        )),
+    }
+}
+
+fn make_struct_impl(ident: &syn::Ident, data: &DataStruct) -> syn::Result<SerializeBodies> {
+    if let Fields::Unnamed(fields) = &data.fields {
+        return Err(syn::Error::new_spanned(
+            fields,
+            "#[derive(Serialize)] needs named fields",
+        ));
+    }
+
+    let FieldsCode {
+        serialize,
+        deserialize,
+        deserialize_borrowed,
+    } = make_fields(&data.fields, |_, field| {
         let ident = &field.ident;
-        let ty = &field.ty;
+        quote! { &self.#ident }
+    })?;
 
-        quote! {
-            #ident: #ty::deserialize(r)?,
+    Ok(SerializeBodies {
+        serialize: quote! {
+            #(#serialize)*
+            ::core::result::Result::Ok(())
+        },
+        deserialize: quote! {
+            ::core::result::Result::Ok(#ident #deserialize)
+        },
+        deserialize_borrowed: quote! {
+            ::core::result::Result::Ok(#ident #deserialize_borrowed)
+        },
+    })
+}
+
+fn make_enum_impl(input: &DeriveInput, data: &DataEnum) -> syn::Result<SerializeBodies> {
+    let ident = &input.ident;
+    let name = ident.to_string();
+    let tag_ty = parse_tag_type(input)?;
+
+    let mut serialize_variants = Vec::new();
+    let mut deserialize_variants = Vec::new();
+    let mut borrowed_variants = Vec::new();
+    let mut unknown_variant = None;
+    let mut seen = HashMap::new();
+    let mut next_tag = 0u64;
+
+    for variant in &data.variants {
+        let v_ident = &variant.ident;
+
+        // Like in Rust, variants without a discriminant follow the previous
+        // one, even if that's the unknown variant.
+        let tag = match &variant.discriminant {
+            Some((_, expr)) => parse_int(expr, "discriminant")?,
+            None => next_tag,
+        };
+        next_tag = tag.wrapping_add(1);
+
+        if let Some(ty) = unknown_variant_tag(variant)? {
+            if ty.to_token_stream().to_string() != tag_ty.to_token_stream().to_string() {
+                return Err(syn::Error::new_spanned(
+                    ty,
+                    format!(
+                        "the unknown variant should hold the tag, which is a {}",
+                        tag_ty.to_token_stream()
+                    ),
+                ));
+            }
+
+            if unknown_variant.is_some() {
+                return Err(syn::Error::new_spanned(
+                    v_ident,
+                    "enum has several unknown variants",
+                ));
+            }
+
+            unknown_variant = Some(v_ident);
+            serialize_variants.push(quote! {
+                #ident::#v_ident(tag) => ::tiki_proto::serialize::Serialize::serialize(tag, w, ctx)?,
+            });
+            continue;
         }
-    });
 
-    let tokens = quote! {
-        impl #impl_generics crate::serialize::Serialize for #ident #ty_generics #where_clause {
-            fn serialize<W: ::std::io::Write>(&self, w: &mut W) {
-                #(#serialize_fields)*
+        if let Some(other) = seen.insert(tag, v_ident) {
+            return Err(syn::Error::new_spanned(
+                v_ident,
+                format!("tag {tag} is already used by {other}"),
+            ));
+        }
+
+        let tag = proc_macro2::Literal::u64_unsuffixed(tag);
+        let (bindings, pattern) = field_bindings(&variant.fields);
+        let FieldsCode {
+            serialize,
+            deserialize,
+            deserialize_borrowed,
+        } = make_fields(&variant.fields, |i, _| bindings[i].to_token_stream())?;
+
+        serialize_variants.push(quote! {
+            #ident::#v_ident #pattern => {
+                <#tag_ty as ::tiki_proto::serialize::Serialize>::serialize(&#tag, w, ctx)?;
+                #(#serialize)*
             }
+        });
+        deserialize_variants.push(quote! {
+            #tag => #ident::#v_ident #deserialize,
+        });
+        borrowed_variants.push(quote! {
+            #tag => #ident::#v_ident #deserialize_borrowed,
+        });
+    }
 
-            fn deserialize<R: ::std::io::Read>(r: &mut R) -> Result<Self, crate::Error> {
-                Ok(#ident {
-                    #(#deserialize_fields)*
+    let unknown = match unknown_variant {
+        Some(v_ident) => quote! { tag => #ident::#v_ident(tag), },
+        None => quote! {
+            tag => {
+                return ::core::result::Result::Err(::tiki_proto::Error::UnknownTag {
+                    ty: #name,
+                    tag: ::core::convert::From::from(tag),
                 })
             }
+        },
+    };
+
+    Ok(SerializeBodies {
+        serialize: quote! {
+            match self {
+                #(#serialize_variants)*
+            }
+            ::core::result::Result::Ok(())
+        },
+        deserialize: quote! {
+            let tag = <#tag_ty as ::tiki_proto::serialize::Serialize>::deserialize(r, ctx)?;
+            ::core::result::Result::Ok(match tag {
+                #(#deserialize_variants)*
+                #unknown
+            })
+        },
+        deserialize_borrowed: quote! {
+            let tag = <#tag_ty as ::tiki_proto::serialize::Serialize>::deserialize(r, ctx)?;
+            ::core::result::Result::Ok(match tag {
+                #(#borrowed_variants)*
+                #unknown
+            })
+        },
+    })
+}
+
+/// Bodies of the `serialize` and `deserialize` methods, and of `deserialize_borrowed`.
+struct SerializeBodies {
+    serialize: proc_macro2::TokenStream,
+    deserialize: proc_macro2::TokenStream,
+    deserialize_borrowed: proc_macro2::TokenStream,
+}
+
+/// Adds the lifetime of the packet to generics, for implementing `Deserialize`.
+///
+/// Types which already have a lifetime borrow for the first one.
+fn borrow_generics(generics: &Generics) -> (Generics, Lifetime) {
+    let mut generics = generics.clone();
+
+    let lifetime = match generics.lifetimes().next() {
+        Some(param) => param.lifetime.clone(),
+        None => {
+            let lifetime = Lifetime::new("'de", proc_macro2::Span::call_site());
+            generics
+                .params
+                .insert(0, LifetimeParam::new(lifetime.clone()).into());
+            lifetime
         }
     };
 
-    tokens.into()
+    let bounded: Vec<_> = generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+
+    for ident in bounded {
+        generics
+            .make_where_clause()
+            .predicates
+            .push(parse_quote! { #ident: ::tiki_proto::serialize::Deserialize<#lifetime> });
+    }
+
+    (generics, lifetime)
+}
+
+fn make_serialize_impl(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let SerializeBodies {
+        serialize,
+        deserialize,
+        deserialize_borrowed,
+    } = match &input.data {
+        Data::Struct(data) => make_struct_impl(ident, data)?,
+        Data::Enum(data) => make_enum_impl(&input, data)?,
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "#[derive(Serialize)] only works with structs and enums",
+            ))
+        }
+    };
+
+    let (borrow_generics, lifetime) = borrow_generics(&input.generics);
+    let (borrow_impl_generics, _, borrow_where_clause) = borrow_generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::tiki_proto::serialize::Serialize for #ident #ty_generics #where_clause {
+            fn serialize<W: ::std::io::Write>(
+                &self,
+                w: &mut W,
+                ctx: &::tiki_proto::serialize::Context,
+            ) -> ::core::result::Result<(), ::tiki_proto::Error> {
+                #serialize
+            }
+
+            fn deserialize<R: ::std::io::Read>(
+                r: &mut R,
+                ctx: &::tiki_proto::serialize::Context,
+            ) -> ::core::result::Result<Self, ::tiki_proto::Error> {
+                #deserialize
+            }
+        }
+
+        impl #borrow_impl_generics ::tiki_proto::serialize::Deserialize<#lifetime> for #ident #ty_generics #borrow_where_clause {
+            fn deserialize_borrowed(
+                r: &mut &#lifetime [u8],
+                ctx: &::tiki_proto::serialize::Context,
+            ) -> ::core::result::Result<Self, ::tiki_proto::Error> {
+                #deserialize_borrowed
+            }
+        }
+    })
+}
+
+/// Implements `Serialize` for a struct by encoding its fields in order.
+///
+/// `Deserialize` is implemented as well, for the packet's lifetime if the
+/// type has one, so fields like `Cow<'a, [u8]>` can borrow from the packet.
+///
+/// Enums are encoded as a tag followed by the fields of the variant. The tag's
+/// type is taken from `#[repr(u8)]`, or given with `#[tag(u16)]` for enums
+/// without a repr. `#[tag = "u16"]` works too, but the type must be quoted,
+/// since Rust only allows literals after the `=`. Each variant's tag is its
+/// discriminant, so data variants need a repr to set it explicitly. A variant
+/// holding just the tag, marked `#[serialize(unknown)]`, receives tags which
+/// no other variant has instead of failing with `Error::UnknownTag`.
+///
+/// Fields can be tweaked with `#[serialize(...)]`:
+///
+/// - `len = u32` prefixes a string or list with its length as the given type,
+///   instead of a u16.
+/// - `with = path` encodes the field with `path::serialize` and `path::deserialize`.
+/// - `since = 42` only encodes the field from the given protocol version on.
+///   Peers using older versions get the field's default value.
+/// - `trailing` reads the field's default value if the packet ends before it,
+///   which can only be the case for the last fields. A field which is cut off
+///   part way is still an error.
+#[proc_macro_derive(Serialize, attributes(serialize, tag))]
+pub fn derive_serialize(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+
+    make_serialize_impl(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
 }
@@ -0,0 +1,9 @@
+//! Checks the errors the macros report for invalid input.
+//!
+//! Run with `TRYBUILD=overwrite` to update the expected output after changing an error.
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,8 @@
+#[derive(tiki_macros::Serialize, Debug)]
+#[tag = 16]
+enum Kind {
+    First,
+    Second,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+#[derive(tiki_macros::Serialize, Debug)]
+#[repr(u8)]
+enum Shape {
+    Point = 1,
+    #[serialize(unknown)]
+    Other(u16),
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+#[derive(tiki_macros::Serialize, Debug)]
+struct Ping {}
+
+#[derive(tiki_macros::Serialize, Debug)]
+struct Pong {}
+
+#[tiki_macros::packet]
+#[derive(Debug)]
+enum Packet {
+    #[id = 0x01]
+    Ping(Ping),
+
+    #[id = 0x01]
+    Pong(Pong),
+}
+
+fn main() {}
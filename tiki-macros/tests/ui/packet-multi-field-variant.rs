@@ -0,0 +1,20 @@
+#[derive(tiki_macros::Serialize, Debug)]
+struct Ping {}
+
+#[derive(tiki_macros::Serialize, Debug)]
+struct Pong {}
+
+#[tiki_macros::packet]
+#[derive(Debug)]
+enum Packet {
+    #[id = 0x01]
+    Ping(Ping, Pong),
+
+    #[id = 0x02]
+    Pong { pong: Pong },
+
+    #[id = 0x03]
+    Empty,
+}
+
+fn main() {}
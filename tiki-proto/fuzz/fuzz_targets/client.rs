@@ -0,0 +1,38 @@
+#![no_main]
+
+//! Feeds datagrams from a hostile server into a client, through the whole
+//! stack: frames, reliability, split reassembly and packet handling.
+
+use std::time::{Duration, Instant};
+
+use libfuzzer_sys::fuzz_target;
+use tiki_proto::{ClientConnectionState, Credentials, Input, Output};
+
+fuzz_target!(|data: &[u8]| {
+    let mut client = ClientConnectionState::new(Credentials {
+        name: "fuzz".to_owned(),
+        password: "fuzz".to_owned(),
+    });
+
+    let mut now = Instant::now();
+    let mut data = data;
+
+    // Each datagram is prefixed with its length as a u16.
+    while let Some((&[hi, lo], rest)) = data.split_first_chunk() {
+        let len = (u16::from_be_bytes([hi, lo]) as usize).min(rest.len());
+        let (datagram, rest) = rest.split_at(len);
+        data = rest;
+
+        while let Output::SendData(_) = client.poll_output(now) {}
+
+        let _ = client.submit_input(Input::ReceivedData(datagram), now);
+        // Received packets are decoded again when read, which must not fail.
+        client
+            .recv_packets()
+            .for_each(|packet| assert!(packet.packet().is_ok()));
+        while client.poll_event().is_some() {}
+
+        now += Duration::from_millis(100);
+        let _ = client.submit_input(Input::TimedOut, now);
+    }
+});
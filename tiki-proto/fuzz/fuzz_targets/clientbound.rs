@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tiki_proto::clientbound::Clientbound;
+use tiki_proto::serialize::{Deserialize, Serialize};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((ctx, mut data)) = common::split_context(data) else {
+        return;
+    };
+
+    let _ = Clientbound::deserialize_borrowed(&mut &data[..], &ctx);
+    let _ = Clientbound::deserialize(&mut data, &ctx);
+});
@@ -0,0 +1,28 @@
+use tiki_proto::serialize::{Context, Limits};
+
+/// Takes the versions to decode with from the first two bytes of the input.
+///
+/// The limits are kept small, so the fuzzer finds out quickly if they aren't checked.
+pub fn split_context(data: &[u8]) -> Option<(Context, &[u8])> {
+    let (&[protocol, serialization], rest) = data.split_first_chunk()?;
+
+    let protocol_versions = tiki_proto::MIN_PROTOCOL_VERSION..=tiki_proto::MAX_PROTOCOL_VERSION;
+    let serialization_versions =
+        tiki_proto::MIN_SERIALIZATION_VERSION..=tiki_proto::MAX_SERIALIZATION_VERSION;
+
+    let ctx = Context {
+        protocol_version: protocol_versions.start()
+            + protocol as u16 % protocol_versions.len() as u16,
+        serialization_version: serialization_versions.start()
+            + serialization % serialization_versions.len() as u8,
+        limits: Limits {
+            max_string_len: 256,
+            max_list_len: 256,
+            max_bytes_len: 4096,
+            max_decompressed_len: 4096,
+            max_split_buffer_len: 4096,
+        },
+    };
+
+    Some((ctx, rest))
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tiki_proto::serialize::{Context, Serialize};
+use tiki_proto::transport::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Frame::deserialize(&mut &data[..], &Context::default());
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tiki_proto::serialize::Serialize;
+use tiki_proto::serverbound::Serverbound;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((ctx, mut data)) = common::split_context(data) else {
+        return;
+    };
+
+    let _ = Serverbound::deserialize(&mut data, &ctx);
+});
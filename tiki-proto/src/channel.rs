@@ -0,0 +1,413 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::serialize::{check_len, Context, Serialize};
+use crate::transport::{
+    Frame, FrameType, Reliability, SplitHeader, TransportError, BASE_HEADER_SIZE, MAX_FRAME_SIZE,
+    RELIABLE_HEADER_SIZE, SPLIT_HEADER_SIZE,
+};
+
+/// Sequence number of the first reliable frame sent on each channel.
+pub const SEQNUM_INITIAL: u16 = 65500;
+
+/// Maximum number of un-acked reliable frames per channel.
+///
+/// The same limit applies to how far ahead of the next expected frame
+/// an incoming reliable frame may be before it is dropped.
+const RELIABLE_WINDOW_SIZE: u16 = 0x400;
+
+/// How long to wait for an ack before sending a reliable frame again,
+/// until a round-trip time estimate is available.
+const INITIAL_RESEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Largest payload that is sent without splitting.
+const MAX_ORIGINAL_PAYLOAD_SIZE: usize =
+    MAX_FRAME_SIZE - BASE_HEADER_SIZE - RELIABLE_HEADER_SIZE - 1;
+
+/// Largest payload of a single split chunk.
+const MAX_CHUNK_SIZE: usize =
+    MAX_FRAME_SIZE - BASE_HEADER_SIZE - RELIABLE_HEADER_SIZE - SPLIT_HEADER_SIZE;
+
+/// How long an incomplete split packet is kept after its last chunk arrived.
+///
+/// This applies to reliable split packets too: their chunks are sent one
+/// after the other, so one which stalls for this long is never completed.
+const SPLIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns whether `seqnum` is in `[next, next + size)`, accounting for wrap-around.
+fn seqnum_in_window(seqnum: u16, next: u16, size: u16) -> bool {
+    seqnum.wrapping_sub(next) < size
+}
+
+struct InFlight {
+    seqnum: u16,
+    data: Vec<u8>,
+    sent_at: Instant,
+    resent: bool,
+}
+
+struct IncomingSplit {
+    chunks: Vec<Option<Vec<u8>>>,
+    chunks_received: usize,
+    size: usize,
+    updated_at: Instant,
+}
+
+/// Room for incomplete split packets, shared by all channels of a peer.
+pub struct SplitBudget {
+    used: usize,
+    max: usize,
+}
+
+impl SplitBudget {
+    pub fn new(max: usize) -> Self {
+        Self { used: 0, max }
+    }
+
+    fn reserve(&mut self, size: usize) -> Result<(), TransportError> {
+        if self.used + size > self.max {
+            return Err(TransportError::SplitBufferFull(self.max));
+        }
+
+        self.used += size;
+        Ok(())
+    }
+
+    fn release(&mut self, size: usize) {
+        self.used -= size;
+    }
+}
+
+/// Reliability state of one channel of a connection.
+///
+/// Outgoing reliable frames get consecutive sequence numbers and are kept
+/// until acked, and are sent again if the ack doesn't arrive in time.
+/// Incoming reliable frames are handed out in sequence order, buffering
+/// ones that arrive early.
+///
+/// Packets too large for one frame are split into chunks, which are
+/// reassembled on the receiving side.
+pub struct Channel {
+    next_outgoing_seqnum: u16,
+    queued: VecDeque<(FrameType, Vec<u8>)>,
+    in_flight: VecDeque<InFlight>,
+    resend_timeout: Duration,
+
+    next_incoming_seqnum: u16,
+    incoming: HashMap<u16, (FrameType, Vec<u8>)>,
+    received: VecDeque<(FrameType, Vec<u8>)>,
+
+    next_split_seqnum: u16,
+    incoming_splits: HashMap<u16, IncomingSplit>,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Self {
+            next_outgoing_seqnum: SEQNUM_INITIAL,
+            queued: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            resend_timeout: INITIAL_RESEND_TIMEOUT,
+
+            next_incoming_seqnum: SEQNUM_INITIAL,
+            incoming: HashMap::new(),
+            received: VecDeque::new(),
+
+            next_split_seqnum: SEQNUM_INITIAL,
+            incoming_splits: HashMap::new(),
+        }
+    }
+
+    /// Splits a packet into frames small enough to be sent on their own.
+    ///
+    /// Packets that fit into a single frame are returned unchanged.
+    /// Fails if the packet needs more chunks than a split header can count.
+    pub fn split(&mut self, payload: Vec<u8>) -> Result<Vec<(FrameType, Vec<u8>)>, crate::Error> {
+        if payload.len() <= MAX_ORIGINAL_PAYLOAD_SIZE {
+            return Ok(vec![(FrameType::Original, payload)]);
+        }
+
+        check_len("packet", payload.len(), u16::MAX as usize * MAX_CHUNK_SIZE)?;
+        let chunk_count = payload.len().div_ceil(MAX_CHUNK_SIZE) as u16;
+
+        let seqnum = self.next_split_seqnum;
+        self.next_split_seqnum = seqnum.wrapping_add(1);
+
+        Ok(payload
+            .chunks(MAX_CHUNK_SIZE)
+            .enumerate()
+            .map(|(chunk_number, chunk)| {
+                let header = SplitHeader {
+                    seqnum,
+                    chunk_count,
+                    chunk_number: chunk_number as u16,
+                };
+
+                (FrameType::Split(header), chunk.to_vec())
+            })
+            .collect())
+    }
+
+    /// Accepts a chunk of a split packet.
+    ///
+    /// Returns the reassembled packet once all of its chunks have arrived.
+    /// Incomplete split packets are buffered in room taken from `budget`.
+    pub fn receive_split(
+        &mut self,
+        header: SplitHeader,
+        chunk: &[u8],
+        now: Instant,
+        budget: &mut SplitBudget,
+    ) -> Result<Option<Vec<u8>>, TransportError> {
+        let SplitHeader {
+            seqnum,
+            chunk_count,
+            chunk_number,
+        } = header;
+
+        if chunk_number >= chunk_count {
+            return Err(TransportError::InvalidSplitChunk {
+                chunk_number,
+                chunk_count,
+            });
+        }
+
+        // Each split packet takes up room for all of its chunks as soon as it's
+        // created, so count that against the limit, not just the received data.
+        let new_size = if self.incoming_splits.contains_key(&seqnum) {
+            chunk.len()
+        } else {
+            chunk.len() + chunk_count as usize * size_of::<Option<Vec<u8>>>()
+        };
+
+        let split = self
+            .incoming_splits
+            .entry(seqnum)
+            .or_insert_with(|| IncomingSplit {
+                chunks: vec![None; chunk_count as usize],
+                chunks_received: 0,
+                size: 0,
+                updated_at: now,
+            });
+
+        if split.chunks.len() != chunk_count as usize {
+            return Err(TransportError::InvalidSplitChunk {
+                chunk_number,
+                chunk_count,
+            });
+        }
+
+        split.updated_at = now;
+
+        let slot = &mut split.chunks[chunk_number as usize];
+        if slot.is_some() {
+            return Ok(None);
+        }
+
+        if let Err(e) = budget.reserve(new_size) {
+            // Don't keep a split packet around which nothing has been reserved for.
+            if split.chunks_received == 0 {
+                self.incoming_splits.remove(&seqnum);
+            }
+            return Err(e);
+        }
+
+        *slot = Some(chunk.to_vec());
+        split.chunks_received += 1;
+        split.size += new_size;
+
+        if split.chunks_received < split.chunks.len() {
+            return Ok(None);
+        }
+
+        let split = self.incoming_splits.remove(&seqnum).unwrap();
+        budget.release(split.size);
+
+        Ok(Some(split.chunks.into_iter().flatten().flatten().collect()))
+    }
+
+    /// Drops split packets which haven't been completed in time.
+    pub fn remove_timed_out_splits(&mut self, now: Instant, budget: &mut SplitBudget) {
+        self.incoming_splits.retain(|_, split| {
+            let timed_out = now >= split.updated_at + SPLIT_TIMEOUT;
+            if timed_out {
+                budget.release(split.size);
+            }
+            !timed_out
+        });
+    }
+
+    /// Queues a frame for reliable delivery.
+    pub fn push_reliable(&mut self, ty: FrameType, payload: Vec<u8>) {
+        self.queued.push_back((ty, payload));
+    }
+
+    /// Returns the next queued reliable frame, if the window has room for it.
+    pub fn poll_transmit(&mut self, peer_id: u16, channel: u8, now: Instant) -> Option<Vec<u8>> {
+        if self.in_flight.len() >= RELIABLE_WINDOW_SIZE as usize {
+            return None;
+        }
+
+        let (ty, payload) = self.queued.pop_front()?;
+
+        let seqnum = self.next_outgoing_seqnum;
+        self.next_outgoing_seqnum = seqnum.wrapping_add(1);
+
+        let frame = Frame {
+            peer_id,
+            channel,
+            reliability: Reliability::Reliable { seqnum },
+            ty,
+        };
+
+        // Frame headers are encoded the same way in every protocol version.
+        let mut data = Vec::new();
+        frame
+            .serialize(&mut data, &Context::default())
+            .expect("frame headers always fit");
+        data.extend_from_slice(&payload);
+
+        self.in_flight.push_back(InFlight {
+            seqnum,
+            data: data.clone(),
+            sent_at: now,
+            resent: false,
+        });
+
+        Some(data)
+    }
+
+    /// Returns frames which weren't acked in time, and marks them as sent again.
+    pub fn poll_resend(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let resend_timeout = self.resend_timeout;
+
+        self.in_flight
+            .iter_mut()
+            .filter(|frame| now >= frame.sent_at + resend_timeout)
+            .map(|frame| {
+                frame.sent_at = now;
+                frame.resent = true;
+                frame.data.clone()
+            })
+            .collect()
+    }
+
+    pub fn set_resend_timeout(&mut self, resend_timeout: Duration) {
+        self.resend_timeout = resend_timeout;
+    }
+
+    /// Removes an acked frame from the window.
+    ///
+    /// Returns the round-trip time of the frame, unless it has been sent more
+    /// than once, in which case it's unknown which of the copies got acked.
+    pub fn handle_ack(&mut self, seqnum: u16, now: Instant) -> Option<Duration> {
+        let index = self
+            .in_flight
+            .iter()
+            .position(|frame| frame.seqnum == seqnum)?;
+
+        let frame = self.in_flight.remove(index)?;
+
+        (!frame.resent).then(|| now.saturating_duration_since(frame.sent_at))
+    }
+
+    /// Returns the earliest time at which an un-acked frame should be sent again.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.in_flight
+            .iter()
+            .map(|frame| frame.sent_at + self.resend_timeout)
+            .min()
+    }
+
+    /// Accepts an incoming reliable frame.
+    ///
+    /// Returns whether the frame should be acked. Frames too far ahead of the
+    /// next expected one are dropped without an ack, so the peer sends them again.
+    pub fn receive_reliable(&mut self, seqnum: u16, ty: FrameType, payload: Vec<u8>) -> bool {
+        if !seqnum_in_window(seqnum, self.next_incoming_seqnum, RELIABLE_WINDOW_SIZE) {
+            // Either a duplicate of a frame we've already handled, whose ack
+            // got lost, or a frame we have no room for.
+            return !seqnum_in_window(seqnum, self.next_incoming_seqnum, 0x8000);
+        }
+
+        self.incoming.entry(seqnum).or_insert((ty, payload));
+
+        while let Some(frame) = self.incoming.remove(&self.next_incoming_seqnum) {
+            self.received.push_back(frame);
+            self.next_incoming_seqnum = self.next_incoming_seqnum.wrapping_add(1);
+        }
+
+        true
+    }
+
+    /// Returns the next reliable frame in sequence order.
+    pub fn poll_received(&mut self) -> Option<(FrameType, Vec<u8>)> {
+        self.received.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_seqnums() {
+        let now = Instant::now();
+        let mut sender = Channel::new();
+        let mut receiver = Channel::new();
+
+        // Starting at SEQNUM_INITIAL, the sequence numbers wrap to 0 after 36 frames.
+        for i in 0..100u8 {
+            sender.push_reliable(FrameType::Original, vec![i]);
+        }
+
+        for i in 0..100u16 {
+            assert!(sender.poll_transmit(1, 0, now).is_some());
+            assert!(sender
+                .handle_ack(SEQNUM_INITIAL.wrapping_add(i), now)
+                .is_some());
+        }
+
+        // Deliver the frames around the wrap-around out of order.
+        let mut seqnums: Vec<u16> = (0..100).map(|i| SEQNUM_INITIAL.wrapping_add(i)).collect();
+        seqnums[30..40].reverse();
+        assert!(seqnums[30..40].contains(&u16::MAX) && seqnums[30..40].contains(&0));
+
+        for seqnum in seqnums {
+            let payload = vec![seqnum.wrapping_sub(SEQNUM_INITIAL) as u8];
+            assert!(receiver.receive_reliable(seqnum, FrameType::Original, payload));
+        }
+
+        for i in 0..100u8 {
+            let (_, payload) = receiver.poll_received().unwrap();
+            assert_eq!(payload, [i]);
+        }
+        assert!(receiver.poll_received().is_none());
+
+        // Frames from before the wrap-around are now duplicates, and are acked again.
+        assert!(receiver.receive_reliable(u16::MAX, FrameType::Original, vec![0]));
+        assert!(receiver.poll_received().is_none());
+    }
+
+    #[test]
+    fn stops_sending_when_the_window_is_full() {
+        let now = Instant::now();
+        let mut channel = Channel::new();
+
+        for _ in 0..RELIABLE_WINDOW_SIZE + 1 {
+            channel.push_reliable(FrameType::Original, vec![0]);
+        }
+
+        for _ in 0..RELIABLE_WINDOW_SIZE {
+            assert!(channel.poll_transmit(1, 0, now).is_some());
+        }
+        assert!(channel.poll_transmit(1, 0, now).is_none());
+
+        // Acking any frame in the window makes room for the next one.
+        assert!(channel
+            .handle_ack(SEQNUM_INITIAL.wrapping_add(5), now)
+            .is_some());
+        assert!(channel.poll_transmit(1, 0, now).is_some());
+        assert!(channel.poll_transmit(1, 0, now).is_none());
+    }
+}
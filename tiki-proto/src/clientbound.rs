@@ -1,180 +1,249 @@
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
 use tiki_macros::Serialize;
 
-use crate::common::AuthMechs;
+use crate::common::{AuthMechs, V3f, V3s16};
+use crate::serialize::{
+    impl_deserialize_owned, Context, Deserialize, LongBytes, RemainingBytes, Serialize, WideString,
+};
 use crate::Error;
 
+/// Packets sent by the server.
+///
+/// Map blocks and media go on their own channel, so that they don't hold up
+/// everything else.
 #[tiki_macros::packet]
 #[derive(Debug)]
-pub enum Clientbound {
+pub enum Clientbound<'a> {
     #[id = 0x02]
+    #[reliable]
     Hello(Hello),
 
     #[id = 0x03]
+    #[reliable]
     AuthAccept(AuthAccept),
 
     #[id = 0x04]
+    #[reliable]
     AcceptSudoMode(AcceptSudoMode),
 
     #[id = 0x05]
+    #[reliable]
     DenySudoMode(DenySudoMode),
 
     #[id = 0x0A]
+    #[reliable]
     AccessDenied(AccessDenied),
 
     #[id = 0x20]
-    BlockData(BlockData),
+    #[channel = 2]
+    #[reliable]
+    BlockData(BlockData<'a>),
 
     #[id = 0x21]
+    #[reliable]
     AddNode(AddNode),
 
     #[id = 0x22]
+    #[reliable]
     RemoveNode(RemoveNode),
 
     #[id = 0x27]
+    #[reliable]
     Inventory(Inventory),
 
     #[id = 0x29]
+    #[reliable]
     TimeOfDay(TimeOfDay),
 
     #[id = 0x2A]
+    #[reliable]
     CsmRestrictionFlags(CsmRestrictionFlags),
 
     #[id = 0x2B]
+    #[reliable]
     PlayerSpeed(PlayerSpeed),
 
     #[id = 0x2C]
+    #[reliable]
     MediaPush(MediaPush),
 
     #[id = 0x2F]
+    #[reliable]
     ChatMessage(ChatMessage),
 
     #[id = 0x31]
+    #[reliable]
     ActiveObjectRemoveAdd(ActiveObjectRemoveAdd),
 
     #[id = 0x32]
-    ActiveObjectMessages(ActiveObjectMessages),
+    #[reliable]
+    ActiveObjectMessages(ActiveObjectMessages<'a>),
 
     #[id = 0x33]
+    #[reliable]
     Hp(Hp),
 
     #[id = 0x34]
+    #[reliable]
     MovePlayer(MovePlayer),
 
     #[id = 0x35]
+    #[reliable]
     AccessDeniedLegacy(AccessDeniedLegacy),
 
     #[id = 0x36]
+    #[reliable]
     Fov(Fov),
 
     #[id = 0x37]
+    #[reliable]
     DeathScreen(DeathScreen),
 
     #[id = 0x38]
+    #[channel = 2]
+    #[reliable]
     Media(Media),
 
     #[id = 0x3A]
+    #[reliable]
     NodeDef(NodeDef),
 
     #[id = 0x3C]
+    #[reliable]
     AnnounceMedia(AnnounceMedia),
 
     #[id = 0x3D]
+    #[reliable]
     ItemDef(ItemDef),
 
     #[id = 0x3F]
+    #[reliable]
     PlaySound(PlaySound),
 
     #[id = 0x40]
+    #[reliable]
     StopSound(StopSound),
 
     #[id = 0x41]
+    #[reliable]
     Privileges(Privileges),
 
     #[id = 0x42]
+    #[reliable]
     InventoryFormSpec(InventoryFormSpec),
 
     #[id = 0x43]
+    #[reliable]
     DetachedInventory(DetachedInventory),
 
     #[id = 0x44]
+    #[reliable]
     ShowFormspec(ShowFormspec),
 
     #[id = 0x45]
+    #[reliable]
     Movement(Movement),
 
     #[id = 0x46]
+    #[reliable]
     SpawnParticle(SpawnParticle),
 
     #[id = 0x47]
+    #[reliable]
     AddParticleSpawner(AddParticleSpawner),
 
     #[id = 0x49]
+    #[reliable]
     HudAdd(HudAdd),
 
     #[id = 0x4A]
+    #[reliable]
     HudRm(HudRm),
 
     #[id = 0x4B]
+    #[reliable]
     HudChange(HudChange),
 
     #[id = 0x4E]
+    #[reliable]
     Breath(Breath),
 
     #[id = 0x4F]
+    #[reliable]
     SetSky(SetSky),
 
     #[id = 0x50]
+    #[reliable]
     OverrideDayNightRatio(OverrideDayNightRatio),
 
     #[id = 0x51]
+    #[reliable]
     LocalPlayerAnimations(LocalPlayerAnimations),
 
     #[id = 0x52]
+    #[reliable]
     EyeOffset(EyeOffset),
 
     #[id = 0x53]
+    #[reliable]
     DeleteParticleSpawner(DeleteParticleSpawner),
 
     #[id = 0x54]
+    #[reliable]
     CloudParams(CloudParams),
 
     #[id = 0x55]
+    #[reliable]
     FadeSound(FadeSound),
 
     #[id = 0x56]
+    #[reliable]
     UpdatePlayerList(UpdatePlayerList),
 
     #[id = 0x57]
+    #[reliable]
     ModChannelMsg(ModChannelMsg),
 
     #[id = 0x58]
+    #[reliable]
     ModChannelSignal(ModChannelSignal),
 
     #[id = 0x59]
+    #[reliable]
     NodeMetaChanged(NodeMetaChanged),
 
     #[id = 0x5A]
+    #[reliable]
     SetSun(SetSun),
 
     #[id = 0x5B]
+    #[reliable]
     SetMoon(SetMoon),
 
     #[id = 0x5C]
+    #[reliable]
     SetStars(SetStars),
 
     #[id = 0x5D]
+    #[reliable]
     MovePlayerRel(MovePlayerRel),
 
     #[id = 0x60]
+    #[reliable]
     SrpBytesSB(SrpBytesSB),
 
     #[id = 0x61]
+    #[reliable]
     FormspecPrepend(FormspecPrepend),
 
     #[id = 0x62]
+    #[reliable]
     MinimapModes(MinimapModes),
 
     #[id = 0x63]
+    #[reliable]
     SetLighting(SetLighting),
 }
 
@@ -188,19 +257,64 @@ pub struct Hello {
 }
 
 #[derive(Serialize, Debug)]
-pub struct AuthAccept {}
+pub struct AuthAccept {
+    pub player_pos: V3f,
+    pub map_seed: u64,
+    pub recommended_send_interval: f32,
+    /// Mechanisms the client may use to enter sudo mode.
+    pub sudo_auth_mechs: AuthMechs,
+}
 
 #[derive(Serialize, Debug)]
-pub struct AcceptSudoMode {}
+pub struct AcceptSudoMode {
+    pub sudo_auth_mechs: AuthMechs,
+}
 
 #[derive(Serialize, Debug)]
 pub struct DenySudoMode {}
 
+/// Why the server refused or closed the connection.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AccessDeniedCode {
+    WrongPassword = 0,
+    UnexpectedData = 1,
+    Singleplayer = 2,
+    WrongVersion = 3,
+    WrongCharsInName = 4,
+    WrongName = 5,
+    TooManyUsers = 6,
+    EmptyPassword = 7,
+    AlreadyConnected = 8,
+    ServerFail = 9,
+    CustomString = 10,
+    Shutdown = 11,
+    Crash = 12,
+    /// A code added in a newer protocol version.
+    #[serialize(unknown)]
+    Unknown(u8),
+}
+
 #[derive(Serialize, Debug)]
-pub struct AccessDenied {}
+pub struct AccessDenied {
+    pub code: AccessDeniedCode,
+    /// Message to show to the player, which older servers only send for some codes.
+    #[serialize(trailing)]
+    pub reason: String,
+    /// Whether the player should try to connect again, e.g. after a server restart.
+    #[serialize(trailing)]
+    pub reconnect: bool,
+}
 
+/// A map block, sent when it comes into view or changes.
+///
+/// The block itself is decoded with `tiki_world::NetworkBlock`.
 #[derive(Serialize, Debug)]
-pub struct BlockData {}
+pub struct BlockData<'a> {
+    pub pos: V3s16,
+    /// The serialized block, which is only decoded once it's needed.
+    pub data: RemainingBytes<'a>,
+}
 
 #[derive(Serialize, Debug)]
 pub struct AddNode {}
@@ -229,8 +343,45 @@ pub struct ChatMessage {}
 #[derive(Serialize, Debug)]
 pub struct ActiveObjectRemoveAdd {}
 
+/// Updates for any number of active objects, like their position or animation.
+#[derive(Serialize, Debug)]
+pub struct ActiveObjectMessages<'a> {
+    /// The messages one after another, which [`ActiveObjectMessages::messages`] reads.
+    pub data: RemainingBytes<'a>,
+}
+
+impl ActiveObjectMessages<'_> {
+    /// Reads the messages, borrowing their data from the packet.
+    ///
+    /// Stops after the first message which can't be read.
+    pub fn messages(
+        &self,
+        ctx: &Context,
+    ) -> impl Iterator<Item = Result<ActiveObjectMessage<'_>, Error>> {
+        let ctx = *ctx;
+        let mut data = &self.data.0[..];
+
+        std::iter::from_fn(move || {
+            if data.is_empty() {
+                return None;
+            }
+
+            let message = ActiveObjectMessage::deserialize_borrowed(&mut data, &ctx);
+
+            if message.is_err() {
+                data = &[];
+            }
+
+            Some(message)
+        })
+    }
+}
+
 #[derive(Serialize, Debug)]
-pub struct ActiveObjectMessages {}
+pub struct ActiveObjectMessage<'a> {
+    pub id: u16,
+    pub data: Cow<'a, [u8]>,
+}
 
 #[derive(Serialize, Debug)]
 pub struct Hp {}
@@ -238,8 +389,24 @@ pub struct Hp {}
 #[derive(Serialize, Debug)]
 pub struct MovePlayer {}
 
-#[derive(Serialize, Debug)]
-pub struct AccessDeniedLegacy {}
+/// Sent instead of [`AccessDenied`] to clients too old to understand it.
+#[derive(Debug)]
+pub struct AccessDeniedLegacy {
+    pub reason: String,
+}
+
+impl Serialize for AccessDeniedLegacy {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        WideString(self.reason.clone()).serialize(w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let WideString(reason) = WideString::deserialize(r, ctx)?;
+        Ok(Self { reason })
+    }
+}
+
+impl_deserialize_owned!(AccessDeniedLegacy);
 
 #[derive(Serialize, Debug)]
 pub struct Fov {}
@@ -247,17 +414,47 @@ pub struct Fov {}
 #[derive(Serialize, Debug)]
 pub struct DeathScreen {}
 
+/// One of possibly several bunches of media files requested by the client.
 #[derive(Serialize, Debug)]
-pub struct Media {}
+pub struct Media {
+    pub bunch_count: u16,
+    pub bunch_index: u16,
+    /// Unlike most lists, the file count is a u32.
+    #[serialize(len = u32)]
+    pub files: Vec<MediaFile>,
+}
 
 #[derive(Serialize, Debug)]
-pub struct NodeDef {}
+pub struct MediaFile {
+    pub name: String,
+    pub data: LongBytes,
+}
 
+/// Compressed node definitions.
 #[derive(Serialize, Debug)]
-pub struct AnnounceMedia {}
+pub struct NodeDef {
+    pub data: LongBytes,
+}
 
 #[derive(Serialize, Debug)]
-pub struct ItemDef {}
+pub struct AnnounceMedia {
+    pub files: Vec<AnnouncedMedia>,
+    /// Comma-separated URLs the files can also be fetched from over HTTP.
+    pub remote_servers: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AnnouncedMedia {
+    pub name: String,
+    /// Base64-encoded SHA-1 digest of the file.
+    pub sha1: String,
+}
+
+/// Compressed item definitions.
+#[derive(Serialize, Debug)]
+pub struct ItemDef {
+    pub data: LongBytes,
+}
 
 #[derive(Serialize, Debug)]
 pub struct PlaySound {}
@@ -344,7 +541,10 @@ pub struct SetStars {}
 pub struct MovePlayerRel {}
 
 #[derive(Serialize, Debug)]
-pub struct SrpBytesSB {}
+pub struct SrpBytesSB {
+    pub salt: Vec<u8>,
+    pub bytes_b: Vec<u8>,
+}
 
 #[derive(Serialize, Debug)]
 pub struct FormspecPrepend {}
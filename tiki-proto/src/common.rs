@@ -1,7 +1,8 @@
 use bitflags::bitflags;
-use crate::serialize::Serialize;
+use crate::serialize::{impl_deserialize_owned, Context, Serialize};
+use tiki_macros::Serialize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct AuthMechs(u32);
 
 bitflags! {
@@ -13,11 +14,68 @@ bitflags! {
 }
 
 impl Serialize for AuthMechs {
-    fn serialize<W: std::io::Write>(&self, w: &mut W) {
-        self.bits().serialize(w);
+    fn serialize<W: std::io::Write>(&self, w: &mut W, ctx: &Context) -> Result<(), crate::Error> {
+        self.bits().serialize(w, ctx)
     }
 
-    fn deserialize<R: std::io::Read>(r: &mut R) -> Result<Self, crate::Error> {
-        Ok(Self::from_bits_truncate(u32::deserialize(r)?))
+    fn deserialize<R: std::io::Read>(r: &mut R, ctx: &Context) -> Result<Self, crate::Error> {
+        Ok(Self::from_bits_truncate(u32::deserialize(r, ctx)?))
     }
 }
+
+impl_deserialize_owned!(AuthMechs);
+
+/// A 2D vector of floats.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct V2f {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// A 3D vector of floats, e.g. a position in nodes times 10.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct V3f {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// A 3D vector of 16-bit integers, e.g. the position of a node or block.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct V3s16 {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+/// A 3D vector sent as fixed-point numbers, with three decimal places each.
+///
+/// Some packets use this instead of [`V3f`] for historical reasons.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct V3f1000(pub V3f);
+
+impl Serialize for V3f1000 {
+    fn serialize<W: std::io::Write>(&self, w: &mut W, ctx: &Context) -> Result<(), crate::Error> {
+        for c in [self.0.x, self.0.y, self.0.z] {
+            ((c * 1000.0) as i32).serialize(w, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn deserialize<R: std::io::Read>(r: &mut R, ctx: &Context) -> Result<Self, crate::Error> {
+        let [x, y, z] = <[i32; 3]>::deserialize(r, ctx)?.map(|c| c as f32 / 1000.0);
+        Ok(Self(V3f { x, y, z }))
+    }
+}
+
+impl_deserialize_owned!(V3f1000);
+
+/// A color with alpha, sent as ARGB with a byte per channel.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub a: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
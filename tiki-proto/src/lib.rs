@@ -1,14 +1,30 @@
-use std::collections::VecDeque;
-use std::io::{Cursor, Read};
-
-use crate::clientbound::Clientbound;
-use crate::serialize::Serialize;
-use crate::serverbound::{Hello, Init, Init2, Serverbound};
-use crate::transport::{ControlHeader, Frame, FrameType, Reliability, TransportError};
-
+// Lets the code generated by tiki-macros name this crate the same way everywhere.
+extern crate self as tiki_proto;
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use crate::clientbound::{AccessDenied, AccessDeniedCode, Clientbound};
+use crate::common::AuthMechs;
+use crate::packet::{packet_id, ReceivedPacket};
+use crate::peer::{Incoming, Peer};
+use crate::serialize::{Context, Deserialize, Limits, Serialize};
+use crate::serverbound::{
+    ClientReady, FirstSrp, Hello, Init, Init2, RequestMedia, Serverbound, SrpBytesA, SrpBytesM,
+};
+use crate::srp::{SrpClient, SrpError};
+use crate::transport::{TransportError, PEER_ID_INEXISTENT};
+
+mod channel;
 pub mod clientbound;
+pub mod packet;
+mod peer;
 pub mod serialize;
+pub mod server;
 pub mod serverbound;
+pub mod srp;
 pub mod transport;
 pub mod common;
 
@@ -25,6 +41,26 @@ pub enum Error {
 
     #[error("unexpected non-Unicode string: {0:?}")]
     NonUnicodeString(Vec<u8>),
+
+    #[error("{what} of length {len} exceeds the limit of {limit}")]
+    LimitExceeded {
+        what: &'static str,
+        len: usize,
+        limit: usize,
+    },
+
+    #[error("invalid {0}")]
+    InvalidValue(&'static str),
+
+    #[error("unknown {ty} tag: {tag}")]
+    UnknownTag { ty: &'static str, tag: u64 },
+
+    #[error("{what} of length {len} is longer than the maximum of {max}")]
+    TooLong {
+        what: &'static str,
+        len: usize,
+        max: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -36,33 +72,228 @@ pub enum Input<'a> {
 #[derive(Debug)]
 pub enum Output {
     SendData(Vec<u8>),
-    Disconnect,
+    Disconnect(DisconnectReason),
     Wait,
 }
 
+/// How often `Init` is sent again while waiting for the server's `Hello`.
+const INIT_RESEND_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Why the connection was closed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server closed the connection without saying why.
+    #[error("connection closed by server")]
+    Closed,
+
+    /// Nothing was received from the server for longer than [`ClientConfig::timeout`].
+    #[error("connection timed out")]
+    TimedOut,
+
+    /// Logging in failed, including because of a wrong password.
+    #[error("authentication failed: {0}")]
+    AuthFailed(AuthError),
+
+    /// The server doesn't support any protocol version the client does.
+    #[error("server uses an incompatible protocol version")]
+    VersionMismatch,
+
+    #[error("server shut down: {message}")]
+    Shutdown { message: String, reconnect: bool },
+
+    #[error("server crashed: {message}")]
+    Crash { message: String, reconnect: bool },
+
+    /// The server sent a message of its own, e.g. when kicking the player.
+    #[error("{0}")]
+    Custom(String),
+
+    /// The server refused the connection for some other reason.
+    #[error("access denied ({code:?}): {message}")]
+    AccessDenied {
+        code: AccessDeniedCode,
+        message: String,
+    },
+
+    /// A packet needed to log in couldn't be encoded, e.g. because the player name is too long.
+    #[error("failed to send packet: {0}")]
+    SendFailed(String),
+}
+
+impl DisconnectReason {
+    /// Returns whether the server suggested connecting again, e.g. because it's restarting.
+    pub fn reconnect_suggested(&self) -> bool {
+        match self {
+            DisconnectReason::Shutdown { reconnect, .. }
+            | DisconnectReason::Crash { reconnect, .. } => *reconnect,
+            _ => false,
+        }
+    }
+}
+
+impl From<&AccessDenied> for DisconnectReason {
+    fn from(denied: &AccessDenied) -> Self {
+        let message = denied.reason.clone();
+
+        match denied.code {
+            AccessDeniedCode::WrongPassword => {
+                DisconnectReason::AuthFailed(AuthError::WrongPassword)
+            }
+            AccessDeniedCode::WrongVersion => DisconnectReason::VersionMismatch,
+            AccessDeniedCode::Shutdown => DisconnectReason::Shutdown {
+                message,
+                reconnect: denied.reconnect,
+            },
+            AccessDeniedCode::Crash => DisconnectReason::Crash {
+                message,
+                reconnect: denied.reconnect,
+            },
+            AccessDeniedCode::CustomString => DisconnectReason::Custom(message),
+            code => DisconnectReason::AccessDenied { code, message },
+        }
+    }
+}
+
+/// Things that happened on the connection which the game may want to react to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The server accepted the old password and the new one has been sent.
+    PasswordChanged,
+    /// The server rejected the old password, so the password wasn't changed.
+    PasswordChangeDenied,
+    /// Item definitions have been received while joining.
+    ItemDefsReceived,
+    /// Node definitions have been received while joining.
+    NodeDefsReceived,
+    /// Some of the media files requested while joining have been received.
+    MediaProgress { received: usize, total: usize },
+    /// Everything needed to play has been received and the client is now in game.
+    Joined,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("wrong password")]
+    WrongPassword,
+
+    #[error("server offered no supported authentication mechanism")]
+    NoSupportedMechanism,
+
+    #[error("account doesn't exist and registration is disabled")]
+    RegistrationDisabled,
+
+    #[error("not logged in yet")]
+    NotLoggedIn,
+
+    #[error("a password change is already in progress")]
+    PasswordChangeInProgress,
+
+    #[error("SRP error: {0}")]
+    Srp(#[from] SrpError),
+}
+
+pub struct ClientConfig {
+    /// How long the server may stay silent before the connection is considered lost.
+    pub timeout: Duration,
+    /// How long to wait after sending anything before sending a ping.
+    pub ping_interval: Duration,
+    /// Whether to create the account if the server doesn't know it yet.
+    ///
+    /// This is off by default, so a mistyped name fails to log in instead.
+    pub register: bool,
+    /// Language the server should translate text into, or empty for the server default.
+    pub lang: String,
+    /// SHA-1 digests of media files the game already has, which won't be requested.
+    pub cached_media: HashSet<[u8; 20]>,
+    /// Limits on what the server may make the client decode.
+    pub limits: Limits,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            ping_interval: Duration::from_secs(5),
+            register: false,
+            lang: String::new(),
+            cached_media: HashSet::new(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Engine version reported to the server once joined.
+const VERSION_MAJOR: u8 = 5;
+const VERSION_MINOR: u8 = 9;
+const VERSION_PATCH: u8 = 0;
+const FULL_VERSION: &str = concat!("tiki ", env!("CARGO_PKG_VERSION"));
+
+/// Newest formspec version the client claims to understand.
+const FORMSPEC_VERSION: u16 = 7;
+
+/// Range of network protocol versions the client can speak.
+///
+/// 37 is what 5.0 servers use, 44 what 5.9 servers use.
+pub const MIN_PROTOCOL_VERSION: u16 = 37;
+pub const MAX_PROTOCOL_VERSION: u16 = 44;
+
+/// Range of serialization versions, used for map blocks and node definitions.
+pub const MIN_SERIALIZATION_VERSION: u8 = 28;
+pub const MAX_SERIALIZATION_VERSION: u8 = 29;
+
+/// Compression modes for the whole connection the client supports.
+///
+/// No such modes exist besides "none"; compressed data is compressed per packet.
+const SUPPORTED_COMPRESSION_MODES: u16 = 0;
+
 /// States for connection state machine.
 #[derive(Debug, PartialEq, Eq)]
 enum Phase {
     SendHello,
+    AwaitPeerId,
     AwaitHello,
     SendAuth1,
     RecvAuth1,
     SendAuth2,
     RecvAuth2,
+    Authenticated,
     ReceivingMedia,
     InGame,
     Disconnected,
 }
 
+/// A password change which is waiting for the server to enter sudo mode.
+struct PasswordChange {
+    srp: SrpClient,
+    new_password: String,
+}
+
 pub struct ClientConnectionState {
     phase: Phase,
+    disconnect_reason: Option<DisconnectReason>,
+
+    peer: Peer,
+    context: Context,
+
+    init_sent_at: Option<Instant>,
 
-    peer_id: u16,
+    auth_mech: AuthMechs,
+    sudo_auth_mechs: AuthMechs,
+    srp: Option<SrpClient>,
+    srp_proof: Option<Vec<u8>>,
+    password_change: Option<PasswordChange>,
 
-    send_queue: VecDeque<Vec<u8>>,
-    recv_packet_queue: VecDeque<Clientbound>,
+    itemdefs_received: bool,
+    nodedefs_received: bool,
+    /// Media files requested but not received yet, once the server has announced them.
+    missing_media: Option<HashSet<String>>,
+    requested_media_count: usize,
+
+    recv_packet_queue: VecDeque<ReceivedPacket>,
+    events: VecDeque<Event>,
 
     credentials: Credentials,
+    config: ClientConfig,
 }
 
 pub struct Credentials {
@@ -72,143 +303,562 @@ pub struct Credentials {
 
 impl ClientConnectionState {
     pub fn new(credentials: Credentials) -> Self {
+        Self::with_config(credentials, ClientConfig::default())
+    }
+
+    pub fn with_config(credentials: Credentials, config: ClientConfig) -> Self {
         Self {
             phase: Phase::SendHello,
+            disconnect_reason: None,
+
+            peer: Peer::new(PEER_ID_INEXISTENT, &config.limits),
+            context: Context {
+                limits: config.limits,
+                ..Default::default()
+            },
+
+            init_sent_at: None,
+
+            auth_mech: AuthMechs::empty(),
+            sudo_auth_mechs: AuthMechs::empty(),
+            srp: None,
+            srp_proof: None,
+            password_change: None,
 
-            peer_id: 0,
+            itemdefs_received: false,
+            nodedefs_received: false,
+            missing_media: None,
+            requested_media_count: 0,
 
-            send_queue: VecDeque::new(),
             recv_packet_queue: VecDeque::new(),
+            events: VecDeque::new(),
 
             credentials,
+            config,
         }
     }
 
-    pub fn submit_input(&mut self, input: Input) -> Result<(), crate::Error> {
-        println!("input: {:?}", input);
+    /// Returns the smoothed round-trip time to the server, once it has been measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.peer.rtt()
+    }
+
+    /// Returns the versions negotiated with the server.
+    ///
+    /// These are needed to (de)serialize packets outside of the connection,
+    /// e.g. to store them. Both are zero until the server's `Hello` arrives.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Returns why the connection was closed, if it has been.
+    pub fn disconnect_reason(&self) -> Option<&DisconnectReason> {
+        self.disconnect_reason.as_ref()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Changes the account's password.
+    ///
+    /// The server has to be put into sudo mode first, which takes another SRP
+    /// exchange with the old password. The outcome is reported as either
+    /// [`Event::PasswordChanged`] or [`Event::PasswordChangeDenied`].
+    pub fn change_password(
+        &mut self,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<(), AuthError> {
+        if !matches!(
+            self.phase,
+            Phase::Authenticated | Phase::ReceivingMedia | Phase::InGame
+        ) {
+            return Err(AuthError::NotLoggedIn);
+        }
+
+        if self.password_change.is_some() {
+            return Err(AuthError::PasswordChangeInProgress);
+        }
+
+        let auth_mech = if self.sudo_auth_mechs.contains(AuthMechs::SRP) {
+            AuthMechs::SRP
+        } else if self.sudo_auth_mechs.contains(AuthMechs::LEGACY) {
+            AuthMechs::LEGACY
+        } else {
+            return Err(AuthError::NoSupportedMechanism);
+        };
+
+        let srp = self.start_srp(auth_mech, old_password);
+
+        self.password_change = Some(PasswordChange {
+            srp,
+            new_password: new_password.to_owned(),
+        });
+
+        Ok(())
+    }
+
+    pub fn submit_input(&mut self, input: Input, now: Instant) -> Result<(), crate::Error> {
+        let _span = self.span().entered();
 
         match input {
             Input::ReceivedData(data) => {
-                self.handle_frame(&mut Cursor::new(data))?;
+                tracing::trace!(len = data.len(), "received datagram");
+                self.handle_datagram(data, now)?;
+            }
+            Input::TimedOut => {
+                self.peer.handle_timeout(now);
+                self.check_timeout(now);
             }
-            Input::TimedOut => {}
         }
 
         Ok(())
     }
 
-    pub fn poll_output(&mut self) -> Output {
-        println!("Phase: {:?}", self.phase);
+    pub fn poll_output(&mut self, now: Instant) -> Output {
+        let _span = self.span().entered();
+
+        self.check_timeout(now);
 
         match self.phase {
             Phase::SendHello => {
-                self.send_original(Serverbound::Hello(Hello {}));
-            }
-            Phase::AwaitHello => self.send_original(Serverbound::Init(Init {
-                client_max_serialization_ver: 255,
-                supp_compr_modes: 0,
-                min_net_proto_version: 0,
-                max_net_proto_version: 100,
-                player_name: self.credentials.name.clone(),
-            })),
+                self.send_or_disconnect(Hello {});
+                self.set_phase(Phase::AwaitPeerId);
+            }
+            Phase::AwaitHello
+                if self
+                    .init_sent_at
+                    .is_none_or(|sent_at| now >= sent_at + INIT_RESEND_INTERVAL) =>
+            {
+                self.init_sent_at = Some(now);
+                self.send_or_disconnect(Init {
+                    client_max_serialization_ver: MAX_SERIALIZATION_VERSION,
+                    supp_compr_modes: SUPPORTED_COMPRESSION_MODES,
+                    min_net_proto_version: MIN_PROTOCOL_VERSION,
+                    max_net_proto_version: MAX_PROTOCOL_VERSION,
+                    player_name: self.credentials.name.clone(),
+                });
+            }
+            Phase::SendAuth1 if self.auth_mech == AuthMechs::FIRST_SRP => {
+                let password = self.credentials.password.clone();
+                self.send_verifier(&password);
+
+                // The server accepts the new account right away.
+                self.set_phase(Phase::RecvAuth2);
+            }
+            Phase::SendAuth1 => {
+                let password = self.credentials.password.clone();
+                self.srp = Some(self.start_srp(self.auth_mech, &password));
+                self.set_phase(Phase::RecvAuth1);
+            }
+            Phase::SendAuth2 => {
+                if let Some(bytes_m) = self.srp_proof.take() {
+                    self.send_or_disconnect(SrpBytesM { bytes_m });
+                }
+
+                self.set_phase(Phase::RecvAuth2);
+            }
+            Phase::Authenticated => {
+                let lang = self.config.lang.clone();
+                self.send_or_disconnect(Init2 { lang });
+                self.set_phase(Phase::ReceivingMedia);
+            }
             Phase::Disconnected => {
-                return Output::Disconnect;
+                let reason = self
+                    .disconnect_reason
+                    .clone()
+                    .unwrap_or(DisconnectReason::Closed);
+                return Output::Disconnect(reason);
             }
             _ => {}
         }
 
-        if let Some(buf) = self.send_queue.pop_front() {
+        if self.can_ping() {
+            self.peer.ping_if_due(self.config.ping_interval, now);
+        }
+
+        if let Some(buf) = self.peer.poll_transmit(now) {
             return Output::SendData(buf);
         }
 
         Output::Wait
     }
 
-    fn handle_frame(&mut self, r: &mut impl Read) -> Result<(), crate::Error> {
-        let frame = Frame::deserialize(r)?;
+    /// Returns the time at which [`Input::TimedOut`] should be submitted,
+    /// if nothing is received before then.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        let resend = self.peer.next_resend();
+
+        let peer_timeout = self
+            .peer
+            .last_received_at()
+            .map(|received_at| received_at + self.config.timeout);
+
+        let ping = self
+            .peer
+            .next_ping(self.config.ping_interval)
+            .filter(|_| self.can_ping());
+
+        let init = self
+            .init_sent_at
+            .filter(|_| self.phase == Phase::AwaitHello)
+            .map(|sent_at| sent_at + INIT_RESEND_INTERVAL);
+
+        [resend, peer_timeout, ping, init]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Pings are only sent once the server has assigned us a peer ID.
+    fn can_ping(&self) -> bool {
+        !matches!(
+            self.phase,
+            Phase::SendHello | Phase::AwaitPeerId | Phase::Disconnected
+        )
+    }
 
-        if let Reliability::Reliable { seqnum } = frame.reliability {
-            self.send_ack(frame.channel, seqnum);
+    fn check_timeout(&mut self, now: Instant) {
+        if self.peer.is_timed_out(self.config.timeout, now) && self.phase != Phase::Disconnected {
+            self.disconnect(DisconnectReason::TimedOut);
         }
+    }
 
-        println!("{:?}", frame);
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        if self.disconnect_reason.is_none() {
+            tracing::info!(%reason, "disconnected");
+            self.disconnect_reason = Some(reason);
+        }
+
+        self.set_phase(Phase::Disconnected);
+    }
 
-        match frame.ty {
-            FrameType::Control(control) => match control {
-                ControlHeader::Ack { .. } => {
-                    // re-send un-acked frames
+    fn set_phase(&mut self, phase: Phase) {
+        // Nothing brings a connection back, even if a packet fails to send
+        // halfway through handling something.
+        if self.phase == Phase::Disconnected {
+            return;
+        }
+
+        if self.phase != phase {
+            tracing::debug!(from = ?self.phase, to = ?phase, "phase changed");
+            self.phase = phase;
+        }
+    }
+
+    /// Span to enter while handling anything, so events can be told apart by connection state.
+    fn span(&self) -> tracing::Span {
+        tracing::debug_span!("client", phase = ?self.phase, peer_id = self.peer.peer_id())
+    }
+
+    fn handle_datagram(&mut self, data: &[u8], now: Instant) -> Result<(), crate::Error> {
+        let mut result = self.peer.receive(data, now);
+
+        while let Some(incoming) = self.peer.poll_incoming() {
+            match incoming {
+                // The packets after a bad one have been acked already, so
+                // they must still be handled, or they'd be lost.
+                Incoming::Packet(data) => {
+                    if let Err(e) = self.handle_packet(data) {
+                        tracing::debug!(error = %e, "dropping packet");
+                        result = result.and(Err(e));
+                    }
                 }
-                ControlHeader::SetPeerId { peer_id } => {
-                    if self.phase == Phase::SendHello {
-                        self.peer_id = peer_id;
-                        self.phase = Phase::AwaitHello;
+                Incoming::SetPeerId(peer_id) => {
+                    if self.phase == Phase::AwaitPeerId {
+                        self.peer.set_peer_id(peer_id);
+                        self.set_phase(Phase::AwaitHello);
                     }
                 }
-                ControlHeader::Ping => {
-                    // pong
+                Incoming::Disco => self.disconnect(DisconnectReason::Closed),
+            }
+        }
+
+        result
+    }
+
+    fn handle_packet(&mut self, data: Vec<u8>) -> Result<(), crate::Error> {
+        let id = packet_id(&data);
+        tracing::debug!(
+            id,
+            name = id.and_then(Clientbound::info_by_id).map(|info| info.name),
+            len = data.len(),
+            "received packet"
+        );
+
+        // Handling the packet may change the context, e.g. when the server says hello.
+        let context = self.context;
+        let clientbound = Clientbound::deserialize_borrowed(&mut &data[..], &context)?;
+        tracing::trace!(packet = ?clientbound, "packet dump");
+
+        match clientbound {
+            Clientbound::Hello(ref hello) if self.phase == Phase::AwaitHello => {
+                tracing::debug!(
+                    protocol_version = hello.protocol_version,
+                    serialization_version = hello.serialization_version,
+                    auth_mechs = ?hello.auth_mechs,
+                    "received hello"
+                );
+
+                let protocol_supported =
+                    (MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&hello.protocol_version);
+                let serialization_supported = (MIN_SERIALIZATION_VERSION
+                    ..=MAX_SERIALIZATION_VERSION)
+                    .contains(&hello.serialization_version);
+
+                if !protocol_supported || !serialization_supported {
+                    self.disconnect(DisconnectReason::VersionMismatch);
+                    return Ok(());
                 }
-                ControlHeader::Disco => {
-                    self.phase = Phase::Disconnected;
+
+                self.context.protocol_version = hello.protocol_version;
+                self.context.serialization_version = hello.serialization_version;
+
+                match self.choose_auth_mech(hello.auth_mechs) {
+                    Ok(auth_mech) => {
+                        self.auth_mech = auth_mech;
+                        self.set_phase(Phase::SendAuth1);
+                    }
+                    Err(e) => self.disconnect(DisconnectReason::AuthFailed(e)),
                 }
-            },
-            FrameType::Original => {
-                let clientbound = Clientbound::deserialize(r)?;
-                match clientbound {
-                    Clientbound::Hello(ref hello) => {
-                        println!("Got Clientbound::Hello: {hello:?}");
-                        self.phase = Phase::SendAuth1;
+            }
+            Clientbound::SrpBytesSB(ref sb) if self.phase == Phase::RecvAuth1 => {
+                let Some(srp) = &self.srp else {
+                    return Ok(());
+                };
+
+                match srp.process_challenge(&sb.salt, &sb.bytes_b) {
+                    Ok(bytes_m) => {
+                        self.srp_proof = Some(bytes_m);
+                        self.set_phase(Phase::SendAuth2);
+                    }
+                    Err(e) => self.disconnect(DisconnectReason::AuthFailed(e.into())),
+                }
+            }
+            Clientbound::AuthAccept(ref accept) if self.phase == Phase::RecvAuth2 => {
+                self.srp = None;
+                self.sudo_auth_mechs = accept.sudo_auth_mechs;
+                self.set_phase(Phase::Authenticated);
+            }
+            Clientbound::SrpBytesSB(ref sb) if self.password_change.is_some() => {
+                let Some(change) = &self.password_change else {
+                    return Ok(());
+                };
+
+                match change.srp.process_challenge(&sb.salt, &sb.bytes_b) {
+                    Ok(bytes_m) => {
+                        self.send_or_disconnect(SrpBytesM { bytes_m });
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = %e, "invalid sudo mode challenge");
+
+                        // The exchange can't be aborted, so send a proof that can't
+                        // match. Otherwise the server keeps waiting for one.
+                        self.send_or_disconnect(SrpBytesM {
+                            bytes_m: Vec::new(),
+                        });
+
+                        self.password_change = None;
+                        self.events.push_back(Event::PasswordChangeDenied);
+                    }
+                }
+            }
+            Clientbound::ItemDef(_) if self.phase == Phase::ReceivingMedia => {
+                self.itemdefs_received = true;
+                self.events.push_back(Event::ItemDefsReceived);
+            }
+            Clientbound::NodeDef(_) if self.phase == Phase::ReceivingMedia => {
+                self.nodedefs_received = true;
+                self.events.push_back(Event::NodeDefsReceived);
+            }
+            Clientbound::AnnounceMedia(ref announce) if self.phase == Phase::ReceivingMedia => {
+                let missing: HashSet<_> = announce
+                    .files
+                    .iter()
+                    .filter(|file| !self.is_media_cached(&file.sha1))
+                    .map(|file| file.name.clone())
+                    .collect();
+
+                if !missing.is_empty() {
+                    let files = missing.iter().cloned().collect();
+                    self.send_or_disconnect(RequestMedia { files });
+                }
+
+                self.requested_media_count = missing.len();
+                self.missing_media = Some(missing);
+                self.push_media_progress();
+            }
+            Clientbound::Media(ref media) if self.phase == Phase::ReceivingMedia => {
+                if let Some(missing) = &mut self.missing_media {
+                    for file in &media.files {
+                        missing.remove(&file.name);
                     }
-                    _ => {}
                 }
 
-                self.recv_packet_queue.push_back(clientbound);
+                self.push_media_progress();
+            }
+            Clientbound::AcceptSudoMode(_) => {
+                if let Some(change) = self.password_change.take() {
+                    self.send_verifier(&change.new_password);
+                    self.events.push_back(Event::PasswordChanged);
+                }
+            }
+            Clientbound::DenySudoMode(_) if self.password_change.is_some() => {
+                self.password_change = None;
+                self.events.push_back(Event::PasswordChangeDenied);
+            }
+            Clientbound::AccessDenied(ref denied) => {
+                self.disconnect(denied.into());
             }
-            FrameType::Split(_) => {}
+            Clientbound::AccessDeniedLegacy(ref denied) => {
+                self.disconnect(DisconnectReason::Custom(denied.reason.clone()));
+            }
+            _ => {}
+        }
+
+        self.recv_packet_queue.push_back(ReceivedPacket::new(data, context));
+
+        if self.phase == Phase::ReceivingMedia && self.has_everything_to_join() {
+            self.send_or_disconnect(ClientReady {
+                major: VERSION_MAJOR,
+                minor: VERSION_MINOR,
+                patch: VERSION_PATCH,
+                reserved: 0,
+                full_version: FULL_VERSION.to_owned(),
+                formspec_version: FORMSPEC_VERSION,
+            });
+
+            self.set_phase(Phase::InGame);
+            self.events.push_back(Event::Joined);
         }
 
         Ok(())
     }
 
-    fn send_ack(&mut self, channel: u8, seqnum: u16) {
-        let mut data = Vec::new();
+    fn is_media_cached(&self, sha1_base64: &str) -> bool {
+        BASE64_STANDARD
+            .decode(sha1_base64)
+            .ok()
+            .and_then(|sha1| <[u8; 20]>::try_from(sha1).ok())
+            .is_some_and(|sha1| self.config.cached_media.contains(&sha1))
+    }
 
-        let frame = Frame {
-            peer_id: self.peer_id,
-            channel,
-            reliability: Reliability::Unreliable,
-            ty: FrameType::Control(ControlHeader::Ack { seqnum }),
+    fn push_media_progress(&mut self) {
+        let Some(missing) = &self.missing_media else {
+            return;
         };
 
-        frame.serialize(&mut data);
+        self.events.push_back(Event::MediaProgress {
+            received: self.requested_media_count - missing.len(),
+            total: self.requested_media_count,
+        });
+    }
 
-        self.send_raw(data);
+    fn has_everything_to_join(&self) -> bool {
+        self.itemdefs_received
+            && self.nodedefs_received
+            && self
+                .missing_media
+                .as_ref()
+                .is_some_and(|missing| missing.is_empty())
     }
 
-    fn send_original(&mut self, payload: impl Serialize) {
-        let mut buf = Vec::new();
+    /// Picks the mechanism to log in with from the ones the server offered.
+    ///
+    /// Servers offer `FIRST_SRP` only for accounts that don't exist yet, and
+    /// `LEGACY` only for accounts whose password predates SRP.
+    fn choose_auth_mech(&self, offered: AuthMechs) -> Result<AuthMechs, AuthError> {
+        if offered.contains(AuthMechs::SRP) {
+            Ok(AuthMechs::SRP)
+        } else if offered.contains(AuthMechs::FIRST_SRP) {
+            if self.config.register {
+                Ok(AuthMechs::FIRST_SRP)
+            } else {
+                Err(AuthError::RegistrationDisabled)
+            }
+        } else if offered.contains(AuthMechs::LEGACY) {
+            Ok(AuthMechs::LEGACY)
+        } else {
+            Err(AuthError::NoSupportedMechanism)
+        }
+    }
 
-        let client_hello_packet = Frame {
-            peer_id: self.peer_id,
-            channel: 0,
-            reliability: Reliability::Unreliable,
-            ty: FrameType::Original,
+    /// Sends `A` to start an SRP exchange.
+    ///
+    /// Accounts using the legacy mechanism have the legacy password hash
+    /// in place of the password.
+    fn start_srp(&mut self, auth_mech: AuthMechs, password: &str) -> SrpClient {
+        let name = &self.credentials.name;
+
+        let (password, based_on) = if auth_mech == AuthMechs::LEGACY {
+            (srp::legacy_password_hash(name, password), 0)
+        } else {
+            (password.to_owned(), 1)
         };
 
-        client_hello_packet.serialize(&mut buf);
-        payload.serialize(&mut buf);
+        let srp = SrpClient::new(name, &password);
 
-        self.send_raw(buf);
+        self.send_or_disconnect(SrpBytesA {
+            bytes_a: srp.public_ephemeral(),
+            based_on,
+        });
+
+        srp
     }
 
-    fn send_raw(&mut self, data: Vec<u8>) {
-        self.send_queue.push_back(data);
+    /// Sends a new salt and verifier, either to register or to change the password.
+    fn send_verifier(&mut self, password: &str) {
+        let salt = srp::generate_salt();
+        let verifier = srp::generate_verifier(&self.credentials.name, password, &salt);
+
+        self.send_or_disconnect(FirstSrp {
+            salt: salt.to_vec(),
+            verifier,
+            is_empty: password.is_empty() as u8,
+        });
+    }
+
+    /// Sends a packet the connection can't go on without, disconnecting if it can't be encoded.
+    fn send_or_disconnect(&mut self, packet: impl Into<Serverbound>) {
+        if let Err(e) = self.send_packet(packet) {
+            self.disconnect(DisconnectReason::SendFailed(e.to_string()));
+        }
     }
 
-    pub fn send_packet(&mut self, _packet: impl Serialize) {
-        unimplemented!()
+    /// Queues a packet to be sent to the server.
+    ///
+    /// The channel and reliability are chosen based on the kind of packet.
+    /// Packets sent after disconnecting are dropped. Fails without sending
+    /// anything if the packet can't be encoded, e.g. because a string in it
+    /// is too long.
+    pub fn send_packet(&mut self, packet: impl Into<Serverbound>) -> Result<(), crate::Error> {
+        if self.phase == Phase::Disconnected {
+            return Ok(());
+        }
+
+        let packet = packet.into();
+
+        let mut data = Vec::new();
+        packet.serialize(&mut data, &self.context)?;
+
+        tracing::debug!(
+            id = packet.id(),
+            name = packet.name(),
+            channel = packet.channel(),
+            reliable = packet.is_reliable(),
+            len = data.len(),
+            "sending packet"
+        );
+        tracing::trace!(?packet, "packet dump");
+
+        self.peer.send(packet.channel(), packet.is_reliable(), data)
     }
 
-    pub fn recv_packets<S: Serialize>(&mut self) -> Result<S, crate::Error> {
-        unimplemented!()
+    /// Returns the packets received from the server, in the order they arrived.
+    ///
+    /// Packets are removed from the connection as the iterator is consumed.
+    pub fn recv_packets(&mut self) -> impl Iterator<Item = ReceivedPacket> + '_ {
+        self.recv_packet_queue.drain(..)
     }
 }
@@ -0,0 +1,70 @@
+//! What's known about each type of packet, generated by `#[tiki_macros::packet]`.
+
+use std::fmt;
+
+use crate::clientbound::Clientbound;
+use crate::serialize::{Context, Deserialize};
+
+/// A type of packet, as listed in the `PACKETS` table of a packet enum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// The id the packet is sent with.
+    pub id: u16,
+    /// Name of the packet type, the same as its variant.
+    pub name: &'static str,
+    /// Channel the packet is sent on.
+    pub channel: u8,
+    /// Whether the packet is resent until the peer acknowledges it.
+    pub reliable: bool,
+}
+
+/// Returns the id of a serialized packet, which is how every packet starts.
+pub fn packet_id(data: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(..2)?.try_into().ok()?))
+}
+
+/// A packet received from the server, which keeps the data it was received as.
+///
+/// Reading it with [`ReceivedPacket::packet`] borrows large payloads like
+/// blocks from that data, so they're only copied once they're needed.
+pub struct ReceivedPacket {
+    data: Vec<u8>,
+    /// The context the packet was decoded with when it arrived.
+    context: Context,
+}
+
+impl ReceivedPacket {
+    /// Wraps a packet which has already been decoded successfully with `context`.
+    pub(crate) fn new(data: Vec<u8>, context: Context) -> Self {
+        Self { data, context }
+    }
+
+    pub fn id(&self) -> u16 {
+        packet_id(&self.data).expect("received packets have been decoded")
+    }
+
+    /// Decodes the packet, borrowing its payloads from the received data.
+    ///
+    /// This decodes the data the same way as when it arrived, so it only
+    /// fails if a packet's encoding doesn't decode the same way twice.
+    pub fn packet(&self) -> Result<Clientbound<'_>, crate::Error> {
+        Clientbound::deserialize_borrowed(&mut &self.data[..], &self.context)
+    }
+
+    /// Returns the packet as it was received, including its id.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Debug for ReceivedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.packet() {
+            Ok(packet) => f.debug_tuple("ReceivedPacket").field(&packet).finish(),
+            Err(_) => f
+                .debug_struct("ReceivedPacket")
+                .field("data", &self.data)
+                .finish(),
+        }
+    }
+}
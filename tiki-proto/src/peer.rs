@@ -0,0 +1,390 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use crate::channel::{Channel, SplitBudget};
+use crate::serialize::{Context, Limits, Serialize};
+use crate::transport::{ControlHeader, Frame, FrameType, Reliability, CHANNEL_COUNT};
+
+/// Bounds for the time to wait for an ack, which is a multiple of the round-trip time.
+const MIN_RESEND_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_RESEND_TIMEOUT: Duration = Duration::from_secs(3);
+const RESEND_TIMEOUT_FACTOR: u32 = 4;
+
+/// Something received from the other end, other than acks and pings.
+#[derive(Debug)]
+pub enum Incoming {
+    Packet(Vec<u8>),
+    SetPeerId(u16),
+    Disco,
+}
+
+/// Transport state of one end of a connection.
+///
+/// This takes care of framing, acks, splitting and measuring the round-trip
+/// time, which works the same way on the client and the server. What the
+/// packets mean is up to the connection state machine owning the peer.
+pub struct Peer {
+    /// Peer ID put into outgoing frames, which identifies the sender.
+    peer_id: u16,
+    channels: [Channel; CHANNEL_COUNT],
+    split_budget: SplitBudget,
+
+    rtt: Option<Duration>,
+    last_received_at: Option<Instant>,
+    last_sent_at: Option<Instant>,
+
+    send_queue: VecDeque<Vec<u8>>,
+    incoming: VecDeque<Incoming>,
+}
+
+impl Peer {
+    pub fn new(peer_id: u16, limits: &Limits) -> Self {
+        Self {
+            peer_id,
+            channels: std::array::from_fn(|_| Channel::new()),
+            split_budget: SplitBudget::new(limits.max_split_buffer_len),
+
+            rtt: None,
+            last_received_at: None,
+            last_sent_at: None,
+
+            send_queue: VecDeque::new(),
+            incoming: VecDeque::new(),
+        }
+    }
+
+    pub fn peer_id(&self) -> u16 {
+        self.peer_id
+    }
+
+    pub fn set_peer_id(&mut self, peer_id: u16) {
+        self.peer_id = peer_id;
+    }
+
+    /// Returns the smoothed round-trip time, once it has been measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn last_received_at(&self) -> Option<Instant> {
+        self.last_received_at
+    }
+
+    /// Returns whether nothing has been received for `timeout`.
+    ///
+    /// The first call starts the clock, if nothing has been received yet.
+    pub fn is_timed_out(&mut self, timeout: Duration, now: Instant) -> bool {
+        let received_at = *self.last_received_at.get_or_insert(now);
+        now >= received_at + timeout
+    }
+
+    /// Handles a datagram from the other end.
+    ///
+    /// Packets and control frames which need the owner's attention are
+    /// returned by [`Peer::poll_incoming`]. Frames which can't be handled are
+    /// dropped, and the first error is returned once the others are handled.
+    pub fn receive(&mut self, data: &[u8], now: Instant) -> Result<(), crate::Error> {
+        let mut r = Cursor::new(data);
+        let frame = Frame::deserialize(&mut r, &Context::default())?;
+        let payload = &data[r.position() as usize..];
+
+        tracing::trace!(
+            peer_id = frame.peer_id,
+            channel = frame.channel,
+            reliability = ?frame.reliability,
+            ty = ?frame.ty,
+            len = payload.len(),
+            "received frame"
+        );
+
+        self.last_received_at = Some(now);
+
+        let channel = frame.channel;
+
+        match frame.reliability {
+            Reliability::Reliable { seqnum } => {
+                let channel_state = &mut self.channels[channel as usize];
+
+                if channel_state.receive_reliable(seqnum, frame.ty, payload.to_vec()) {
+                    self.send_ack(channel, seqnum);
+                }
+
+                // A bad frame is dropped, but the frames after it are still
+                // handled, since they won't be sent again once they are acked.
+                let mut result = Ok(());
+                while let Some((ty, payload)) = self.channels[channel as usize].poll_received() {
+                    if let Err(e) = self.handle_frame(channel, ty, &payload, now) {
+                        tracing::debug!(channel, error = %e, "dropping frame");
+                        result = result.and(Err(e));
+                    }
+                }
+
+                result
+            }
+            Reliability::Unreliable => self.handle_frame(channel, frame.ty, payload, now),
+        }
+    }
+
+    pub fn poll_incoming(&mut self) -> Option<Incoming> {
+        self.incoming.pop_front()
+    }
+
+    fn handle_frame(
+        &mut self,
+        channel: u8,
+        ty: FrameType,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<(), crate::Error> {
+        match ty {
+            FrameType::Control(control) => match control {
+                ControlHeader::Ack { seqnum } => {
+                    if let Some(rtt) = self.channels[channel as usize].handle_ack(seqnum, now) {
+                        self.update_rtt(rtt);
+                    }
+                }
+                ControlHeader::SetPeerId { peer_id } => {
+                    self.incoming.push_back(Incoming::SetPeerId(peer_id));
+                }
+                ControlHeader::Ping => {
+                    // Pings are sent reliably, so the ack is the answer.
+                }
+                ControlHeader::Disco => {
+                    self.incoming.push_back(Incoming::Disco);
+                }
+            },
+            FrameType::Original => {
+                self.incoming.push_back(Incoming::Packet(payload.to_vec()));
+            }
+            FrameType::Split(header) => {
+                let split = self.channels[channel as usize].receive_split(
+                    header,
+                    payload,
+                    now,
+                    &mut self.split_budget,
+                )?;
+                if let Some(data) = split {
+                    self.incoming.push_back(Incoming::Packet(data));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let rtt = match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        };
+
+        self.rtt = Some(rtt);
+
+        let resend_timeout =
+            (rtt * RESEND_TIMEOUT_FACTOR).clamp(MIN_RESEND_TIMEOUT, MAX_RESEND_TIMEOUT);
+
+        for channel in &mut self.channels {
+            channel.set_resend_timeout(resend_timeout);
+        }
+    }
+
+    /// Resends reliable frames which weren't acked in time, and drops stale split packets.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            let resent = channel.poll_resend(now);
+
+            if !resent.is_empty() {
+                tracing::debug!(channel = i, count = resent.len(), "resending frames");
+            }
+
+            self.send_queue.extend(resent);
+            channel.remove_timed_out_splits(now, &mut self.split_budget);
+        }
+    }
+
+    /// Queues a ping if nothing has been sent for `interval`.
+    pub fn ping_if_due(&mut self, interval: Duration, now: Instant) {
+        if self
+            .last_sent_at
+            .is_some_and(|sent_at| now >= sent_at + interval)
+        {
+            self.send_control(ControlHeader::Ping, true);
+            // Don't queue another ping if this one can't be sent right away.
+            self.last_sent_at = Some(now);
+        }
+    }
+
+    /// Returns when a ping should be sent, if nothing else is sent before then.
+    pub fn next_ping(&self, interval: Duration) -> Option<Instant> {
+        self.last_sent_at.map(|sent_at| sent_at + interval)
+    }
+
+    /// Returns the earliest time at which an un-acked frame should be sent again.
+    pub fn next_resend(&self) -> Option<Instant> {
+        self.channels
+            .iter()
+            .filter_map(|channel| channel.next_timeout())
+            .min()
+    }
+
+    /// Returns the next datagram to send.
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let data = self.send_queue.pop_front().or_else(|| {
+            self.channels
+                .iter_mut()
+                .enumerate()
+                .find_map(|(i, channel)| channel.poll_transmit(self.peer_id, i as u8, now))
+        })?;
+
+        self.last_sent_at = Some(now);
+
+        Some(data)
+    }
+
+    /// Queues a packet, splitting it if it doesn't fit into a single frame.
+    ///
+    /// Nothing is queued if the packet is too large to be split.
+    pub fn send(
+        &mut self,
+        channel: u8,
+        reliable: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), crate::Error> {
+        for (ty, payload) in self.channels[channel as usize].split(payload)? {
+            self.send_frame(channel, reliable, ty, payload);
+        }
+
+        Ok(())
+    }
+
+    /// Queues a control frame on channel 0.
+    pub fn send_control(&mut self, control: ControlHeader, reliable: bool) {
+        self.send_frame(0, reliable, FrameType::Control(control), Vec::new());
+    }
+
+    fn send_ack(&mut self, channel: u8, seqnum: u16) {
+        self.send_frame(
+            channel,
+            false,
+            FrameType::Control(ControlHeader::Ack { seqnum }),
+            Vec::new(),
+        );
+    }
+
+    fn send_frame(&mut self, channel: u8, reliable: bool, ty: FrameType, payload: Vec<u8>) {
+        if reliable {
+            self.channels[channel as usize].push_reliable(ty, payload);
+            return;
+        }
+
+        let frame = Frame {
+            peer_id: self.peer_id,
+            channel,
+            reliability: Reliability::Unreliable,
+            ty,
+        };
+
+        let mut data = Vec::new();
+        frame
+            .serialize(&mut data, &Context::default())
+            .expect("frame headers always fit");
+        data.extend_from_slice(&payload);
+
+        self.send_queue.push_back(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::SEQNUM_INITIAL;
+    use crate::transport::{SplitHeader, TransportError};
+
+    fn frame(channel: u8, reliability: Reliability, ty: FrameType, payload: &[u8]) -> Vec<u8> {
+        let frame = Frame {
+            peer_id: 1,
+            channel,
+            reliability,
+            ty,
+        };
+
+        let mut data = Vec::new();
+        frame.serialize(&mut data, &Context::default()).unwrap();
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn handles_frames_after_a_bad_one() {
+        let now = Instant::now();
+        let mut peer = Peer::new(2, &Limits::default());
+
+        // Arrives early, so it waits for the bad frame before it.
+        let good = frame(
+            0,
+            Reliability::Reliable {
+                seqnum: SEQNUM_INITIAL + 1,
+            },
+            FrameType::Original,
+            &[0, 1],
+        );
+        peer.receive(&good, now).unwrap();
+        assert!(peer.poll_incoming().is_none());
+
+        let bad_header = SplitHeader {
+            seqnum: SEQNUM_INITIAL,
+            chunk_count: 1,
+            chunk_number: 1,
+        };
+        let bad = frame(
+            0,
+            Reliability::Reliable {
+                seqnum: SEQNUM_INITIAL,
+            },
+            FrameType::Split(bad_header),
+            &[0],
+        );
+        assert!(peer.receive(&bad, now).is_err());
+
+        assert!(matches!(peer.poll_incoming(), Some(Incoming::Packet(data)) if data == [0, 1]));
+        assert!(peer.poll_incoming().is_none());
+    }
+
+    #[test]
+    fn limits_split_packets_across_channels() {
+        let now = Instant::now();
+        let limits = Limits {
+            max_split_buffer_len: 100,
+            ..Default::default()
+        };
+        let mut peer = Peer::new(2, &limits);
+
+        let split = || {
+            FrameType::Split(SplitHeader {
+                seqnum: 0,
+                chunk_count: 2,
+                chunk_number: 0,
+            })
+        };
+        let reliable = Reliability::Reliable {
+            seqnum: SEQNUM_INITIAL,
+        };
+        let first = frame(0, reliable, split(), &[0; 40]);
+        peer.receive(&first, now).unwrap();
+
+        // Another channel has no room of its own.
+        let second = frame(1, Reliability::Unreliable, split(), &[0; 40]);
+        assert!(matches!(
+            peer.receive(&second, now),
+            Err(crate::Error::Transport(TransportError::SplitBufferFull(
+                100
+            )))
+        ));
+
+        // The reliable split packet is never completed, so it's dropped in the end.
+        let later = now + Duration::from_secs(60);
+        peer.handle_timeout(later);
+        peer.receive(&second, later).unwrap();
+    }
+}
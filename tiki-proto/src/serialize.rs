@@ -1,41 +1,151 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Read, Write};
+use std::borrow::Cow;
+use std::io::{Chain, Read, Write};
 
 use crate::Error;
 
+/// Versions negotiated with the peer, which decide how some data is encoded,
+/// and limits on what the peer may make us decode.
+///
+/// The versions are zero until the server's `Hello` has been received. The
+/// packets exchanged before then are encoded the same way in every version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Context {
+    pub protocol_version: u16,
+    pub serialization_version: u8,
+    pub limits: Limits,
+}
+
+/// Upper bounds on sizes read from the peer, so it can't make us allocate
+/// arbitrary amounts of memory.
+///
+/// Exceeding a limit fails with [`Error::LimitExceeded`] before anything is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum length of a string, in bytes.
+    pub max_string_len: usize,
+    /// Maximum number of items in a list.
+    pub max_list_len: usize,
+    /// Maximum length of binary data and strings with a u32 length prefix, like media files.
+    pub max_bytes_len: usize,
+    /// Maximum size of compressed data once it has been decompressed.
+    pub max_decompressed_len: usize,
+    /// Maximum number of bytes buffered in incomplete split packets, across
+    /// all channels of a connection.
+    pub max_split_buffer_len: usize,
+}
+
+impl Limits {
+    /// Limits which every legitimate server stays within.
+    pub const DEFAULT: Self = Self {
+        // Anything with a u16 length is accepted, so these only bound strings
+        // and lists whose length is a u32.
+        max_string_len: u16::MAX as usize,
+        max_list_len: u16::MAX as usize,
+        max_bytes_len: 64 * 1024 * 1024,
+        max_decompressed_len: 64 * 1024 * 1024,
+        // Room for a packet with as much binary data as `max_bytes_len` allows.
+        max_split_buffer_len: 64 * 1024 * 1024,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Fails if `len` is above `limit`, naming what was too long in the error.
+pub fn check_limit(what: &'static str, len: usize, limit: usize) -> Result<(), Error> {
+    if len > limit {
+        return Err(Error::LimitExceeded { what, len, limit });
+    }
+
+    Ok(())
+}
+
+/// Fails if `len` doesn't fit into the length prefix of something we're writing.
+pub fn check_len(what: &'static str, len: usize, max: usize) -> Result<(), Error> {
+    if len > max {
+        return Err(Error::TooLong { what, len, max });
+    }
+
+    Ok(())
+}
+
 pub trait Serialize: Sized {
-    fn serialize<W: Write>(&self, w: &mut W);
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error>;
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error>;
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error>;
+}
+
+/// Reading from a packet which is already in memory, so that large payloads
+/// like blocks can borrow from it instead of being copied.
+///
+/// `#[derive(Serialize)]` implements this too. Fields like `Cow<'a, [u8]>`
+/// borrow from `data`, while everything else is read like with [`Serialize`].
+pub trait Deserialize<'a>: Sized {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error>;
+}
+
+/// Implements [`Deserialize`] for types which never borrow, by reading them with [`Serialize`].
+macro_rules! impl_deserialize_owned {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl<'a> $crate::serialize::Deserialize<'a> for $ty {
+                fn deserialize_borrowed(
+                    data: &mut &'a [u8],
+                    ctx: &$crate::serialize::Context,
+                ) -> Result<Self, $crate::Error> {
+                    <$ty as $crate::serialize::Serialize>::deserialize(data, ctx)
+                }
+            }
+        )*
+    };
 }
 
+pub(crate) use impl_deserialize_owned;
+
+impl_deserialize_owned!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, bool);
+
 impl Serialize for u8 {
-    fn serialize<W: Write>(&self, w: &mut W) {
-        w.write_u8(*self).unwrap();
+    fn serialize<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+        Ok(w.write_u8(*self)?)
     }
 
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error> {
+    fn deserialize<R: Read>(r: &mut R, _ctx: &Context) -> Result<Self, Error> {
         Ok(r.read_u8()?)
     }
 }
 
 impl Serialize for i8 {
-    fn serialize<W: Write>(&self, w: &mut W) {
-        w.write_i8(*self).unwrap();
+    fn serialize<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+        Ok(w.write_i8(*self)?)
     }
 
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error> {
+    fn deserialize<R: Read>(r: &mut R, _ctx: &Context) -> Result<Self, Error> {
         Ok(r.read_i8()?)
     }
 }
 
+/// Booleans are a u8, where anything but 0 is true.
+impl Serialize for bool {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        (*self as u8).serialize(w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        Ok(u8::deserialize(r, ctx)? != 0)
+    }
+}
+
 macro_rules! impl_serialize_for_primitive {
     ($ty:ty, $read:ident, $write:ident) => {
         impl Serialize for $ty {
-            fn serialize<W: Write>(&self, w: &mut W) {
-                w.$write::<BigEndian>(*self).unwrap();
+            fn serialize<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+                Ok(w.$write::<BigEndian>(*self)?)
             }
 
-            fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error> {
+            fn deserialize<R: Read>(r: &mut R, _ctx: &Context) -> Result<Self, Error> {
                 Ok(r.$read::<BigEndian>()?)
             }
         }
@@ -53,19 +163,519 @@ impl_serialize_for_primitive!(i64, read_i64, write_i64);
 impl_serialize_for_primitive!(f32, read_f32, write_f32);
 impl_serialize_for_primitive!(f64, read_f64, write_f64);
 
+/// Integer types which can prefix data with its length.
+///
+/// Packet fields pick one with `#[serialize(len = ...)]`.
+pub trait LengthPrefix: Serialize {
+    const MAX: usize;
+
+    /// Converts a length which has been checked against [`Self::MAX`].
+    fn from_len(len: usize) -> Self;
+    fn to_len(self) -> usize;
+}
+
+macro_rules! impl_length_prefix {
+    ($ty:ty) => {
+        impl LengthPrefix for $ty {
+            const MAX: usize = <$ty>::MAX as usize;
+
+            fn from_len(len: usize) -> Self {
+                len as $ty
+            }
+
+            fn to_len(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+impl_length_prefix!(u8);
+impl_length_prefix!(u16);
+impl_length_prefix!(u32);
+
+/// Data which is sent after its length, with a prefix of any [`LengthPrefix`] type.
+pub trait LengthPrefixed: Sized {
+    /// What the data is called in errors.
+    const WHAT: &'static str;
+
+    /// The number of items the prefix counts, e.g. bytes for strings.
+    fn prefixed_len(&self) -> usize;
+
+    /// How many items the peer may send, given the largest length the prefix can hold.
+    fn limit(limits: &Limits, max: usize) -> usize;
+
+    fn serialize_items<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error>;
+    fn deserialize_items<R: Read>(r: &mut R, len: usize, ctx: &Context) -> Result<Self, Error>;
+}
+
+/// Writes the length of `value` as an `L`, followed by its items.
+pub fn serialize_with_len<L: LengthPrefix, T: LengthPrefixed, W: Write>(
+    value: &T,
+    w: &mut W,
+    ctx: &Context,
+) -> Result<(), Error> {
+    let len = value.prefixed_len();
+    check_len(T::WHAT, len, L::MAX)?;
+
+    L::from_len(len).serialize(w, ctx)?;
+    value.serialize_items(w, ctx)
+}
+
+/// Reads a length as an `L`, followed by that many items.
+pub fn deserialize_with_len<L: LengthPrefix, T: LengthPrefixed, R: Read>(
+    r: &mut R,
+    ctx: &Context,
+) -> Result<T, Error> {
+    let len = L::deserialize(r, ctx)?.to_len();
+    check_limit(T::WHAT, len, T::limit(&ctx.limits, L::MAX))?;
+
+    T::deserialize_items(r, len, ctx)
+}
+
+/// Length-prefixed data which can borrow its items from the packet.
+pub trait BorrowLengthPrefixed<'a>: LengthPrefixed {
+    fn deserialize_items_borrowed(
+        data: &mut &'a [u8],
+        len: usize,
+        ctx: &Context,
+    ) -> Result<Self, Error>;
+}
+
+/// Like [`deserialize_with_len`], but borrowing from the packet where possible.
+pub fn deserialize_borrowed_with_len<'a, L: LengthPrefix, T: BorrowLengthPrefixed<'a>>(
+    data: &mut &'a [u8],
+    ctx: &Context,
+) -> Result<T, Error> {
+    let len = L::deserialize(data, ctx)?.to_len();
+    check_limit(T::WHAT, len, T::limit(&ctx.limits, L::MAX))?;
+
+    T::deserialize_items_borrowed(data, len, ctx)
+}
+
+/// Splits the next `len` bytes off the packet.
+fn take_bytes<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if data.len() < len {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+    }
+
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Ok(bytes)
+}
+
+impl LengthPrefixed for String {
+    const WHAT: &'static str = "string";
+
+    fn prefixed_len(&self) -> usize {
+        self.len()
+    }
+
+    /// Strings with a u32 length may be as long as binary data.
+    fn limit(limits: &Limits, max: usize) -> usize {
+        if max > u16::MAX as usize {
+            limits.max_bytes_len
+        } else {
+            limits.max_string_len
+        }
+    }
+
+    fn serialize_items<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+        Ok(w.write_all(self.as_bytes())?)
+    }
+
+    fn deserialize_items<R: Read>(r: &mut R, len: usize, _ctx: &Context) -> Result<Self, Error> {
+        let data = read_untrusted(r, len)?;
+        String::from_utf8(data).map_err(|e| Error::NonUnicodeString(e.into_bytes()))
+    }
+}
+
+impl<'a> BorrowLengthPrefixed<'a> for String {
+    fn deserialize_items_borrowed(
+        data: &mut &'a [u8],
+        len: usize,
+        ctx: &Context,
+    ) -> Result<Self, Error> {
+        Self::deserialize_items(data, len, ctx)
+    }
+}
+
+impl<T: Serialize> LengthPrefixed for Vec<T> {
+    const WHAT: &'static str = "list";
+
+    fn prefixed_len(&self) -> usize {
+        self.len()
+    }
+
+    fn limit(limits: &Limits, _max: usize) -> usize {
+        limits.max_list_len
+    }
+
+    fn serialize_items<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        for item in self {
+            item.serialize(w, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn deserialize_items<R: Read>(r: &mut R, len: usize, ctx: &Context) -> Result<Self, Error> {
+        (0..len).map(|_| T::deserialize(r, ctx)).collect()
+    }
+}
+
+impl<'a, T: Serialize + Deserialize<'a>> BorrowLengthPrefixed<'a> for Vec<T> {
+    fn deserialize_items_borrowed(
+        data: &mut &'a [u8],
+        len: usize,
+        ctx: &Context,
+    ) -> Result<Self, Error> {
+        (0..len)
+            .map(|_| T::deserialize_borrowed(data, ctx))
+            .collect()
+    }
+}
+
 impl Serialize for String {
-    fn serialize<W: Write>(&self, w: &mut W) {
-        assert!(self.len() <= u16::MAX as usize);
-        (self.len() as u16).serialize(w);
-        w.write_all(self.as_bytes()).unwrap();
-    }
-
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error> {
-        let len = u16::deserialize(r)?;
-        let mut data = vec![0; len as usize];
-        r.read_exact(&mut data)?;
-        String::from_utf8(data).map_err(|e| {
-            Error::NonUnicodeString(e.into_bytes())
-        })
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        serialize_with_len::<u16, _, _>(self, w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_with_len::<u16, _, _>(r, ctx)
+    }
+}
+
+impl_deserialize_owned!(String, LongString, WideString, LongBytes);
+
+impl LengthPrefixed for Cow<'_, str> {
+    const WHAT: &'static str = "string";
+
+    fn prefixed_len(&self) -> usize {
+        self.len()
+    }
+
+    fn limit(limits: &Limits, max: usize) -> usize {
+        String::limit(limits, max)
+    }
+
+    fn serialize_items<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+        Ok(w.write_all(self.as_bytes())?)
+    }
+
+    fn deserialize_items<R: Read>(r: &mut R, len: usize, ctx: &Context) -> Result<Self, Error> {
+        String::deserialize_items(r, len, ctx).map(Cow::Owned)
+    }
+}
+
+impl<'a> BorrowLengthPrefixed<'a> for Cow<'a, str> {
+    fn deserialize_items_borrowed(
+        data: &mut &'a [u8],
+        len: usize,
+        _ctx: &Context,
+    ) -> Result<Self, Error> {
+        let bytes = take_bytes(data, len)?;
+        let s = std::str::from_utf8(bytes).map_err(|_| Error::NonUnicodeString(bytes.to_vec()))?;
+        Ok(Cow::Borrowed(s))
+    }
+}
+
+/// A string which borrows from the packet when read with [`Deserialize`].
+impl Serialize for Cow<'_, str> {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        serialize_with_len::<u16, _, _>(self, w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_with_len::<u16, _, _>(r, ctx)
+    }
+}
+
+impl<'a> Deserialize<'a> for Cow<'a, str> {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error> {
+        deserialize_borrowed_with_len::<u16, _>(data, ctx)
+    }
+}
+
+impl LengthPrefixed for Cow<'_, [u8]> {
+    const WHAT: &'static str = "binary data";
+
+    fn prefixed_len(&self) -> usize {
+        self.len()
+    }
+
+    fn limit(limits: &Limits, _max: usize) -> usize {
+        limits.max_bytes_len
+    }
+
+    fn serialize_items<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+        Ok(w.write_all(self)?)
+    }
+
+    fn deserialize_items<R: Read>(r: &mut R, len: usize, _ctx: &Context) -> Result<Self, Error> {
+        read_untrusted(r, len).map(Cow::Owned)
+    }
+}
+
+impl<'a> BorrowLengthPrefixed<'a> for Cow<'a, [u8]> {
+    fn deserialize_items_borrowed(
+        data: &mut &'a [u8],
+        len: usize,
+        _ctx: &Context,
+    ) -> Result<Self, Error> {
+        take_bytes(data, len).map(Cow::Borrowed)
+    }
+}
+
+/// Binary data prefixed with its length as a u16 like `Vec<u8>`, which borrows
+/// from the packet when read with [`Deserialize`].
+impl Serialize for Cow<'_, [u8]> {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        serialize_with_len::<u16, _, _>(self, w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_with_len::<u16, _, _>(r, ctx)
+    }
+}
+
+impl<'a> Deserialize<'a> for Cow<'a, [u8]> {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error> {
+        deserialize_borrowed_with_len::<u16, _>(data, ctx)
+    }
+}
+
+/// Binary data taking up the rest of the packet, without a length prefix.
+///
+/// Packets like `BlockData` end with their payload. When read with
+/// [`Deserialize`], it stays borrowed from the packet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemainingBytes<'a>(pub Cow<'a, [u8]>);
+
+impl Serialize for RemainingBytes<'_> {
+    fn serialize<W: Write>(&self, w: &mut W, _ctx: &Context) -> Result<(), Error> {
+        Ok(w.write_all(&self.0)?)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let max_len = ctx.limits.max_bytes_len;
+        let mut data = Vec::new();
+        r.take(max_len as u64 + 1).read_to_end(&mut data)?;
+        check_limit("binary data", data.len(), max_len)?;
+
+        Ok(Self(Cow::Owned(data)))
+    }
+}
+
+impl<'a> Deserialize<'a> for RemainingBytes<'a> {
+    fn deserialize_borrowed(data: &mut &'a [u8], _ctx: &Context) -> Result<Self, Error> {
+        Ok(Self(Cow::Borrowed(std::mem::take(data))))
+    }
+}
+
+/// A string prefixed with its length as a u32, used for text which may not fit
+/// into a regular string, like formspecs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LongString(pub String);
+
+impl Serialize for LongString {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        serialize_with_len::<u32, _, _>(&self.0, w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_with_len::<u32, _, _>(r, ctx).map(Self)
+    }
+}
+
+/// A UTF-16 string, prefixed with its length in code units as a u16.
+///
+/// Older servers send some messages like this. Invalid UTF-16 is replaced
+/// with U+FFFD when reading.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WideString(pub String);
+
+impl Serialize for WideString {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        let units: Vec<u16> = self.0.encode_utf16().collect();
+        check_len("wide string", units.len(), u16::MAX as usize)?;
+        units.serialize(w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let len = u16::deserialize(r, ctx)?;
+        check_limit("wide string", len as usize, ctx.limits.max_string_len)?;
+
+        let units = (0..len)
+            .map(|_| u16::deserialize(r, ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self(String::from_utf16_lossy(&units)))
+    }
+}
+
+/// Binary data prefixed with its length as a u32, used for large payloads.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LongBytes(pub Vec<u8>);
+
+impl Serialize for LongBytes {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        check_len("binary data", self.0.len(), u32::MAX as usize)?;
+        (self.0.len() as u32).serialize(w, ctx)?;
+        Ok(w.write_all(&self.0)?)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let len = u32::deserialize(r, ctx)?;
+        check_limit("binary data", len as usize, ctx.limits.max_bytes_len)?;
+
+        Ok(Self(read_untrusted(r, len as usize)?))
+    }
+}
+
+/// Reads `len` bytes without trusting the length to allocate up front.
+fn read_untrusted<R: Read>(r: &mut R, len: usize) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data)?;
+
+    if data.len() != len {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+    }
+
+    Ok(data)
+}
+
+impl<'a, T: Deserialize<'a>, const N: usize> Deserialize<'a> for [T; N] {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error> {
+        let items = (0..N)
+            .map(|_| T::deserialize_borrowed(data, ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+}
+
+impl<T: Serialize, const N: usize> Serialize for [T; N] {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        for item in self {
+            item.serialize(w, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let items = (0..N)
+            .map(|_| T::deserialize(r, ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+}
+
+/// Lists are prefixed with their length as a u16.
+///
+/// This is also how the protocol sends binary strings.
+impl<T: Serialize> Serialize for Vec<T> {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        serialize_with_len::<u16, _, _>(self, w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_with_len::<u16, _, _>(r, ctx)
+    }
+}
+
+impl<'a, T: Serialize + Deserialize<'a>> Deserialize<'a> for Vec<T> {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error> {
+        deserialize_borrowed_with_len::<u16, _>(data, ctx)
+    }
+}
+
+/// A list prefixed with its length as a u32, for lists which may be longer than a u16 allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LongList<T>(pub Vec<T>);
+
+impl<T> Default for LongList<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: Serialize> Serialize for LongList<T> {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        serialize_with_len::<u32, _, _>(&self.0, w, ctx)
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_with_len::<u32, _, _>(r, ctx).map(Self)
+    }
+}
+
+impl<'a, T: Serialize + Deserialize<'a>> Deserialize<'a> for LongList<T> {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error> {
+        deserialize_borrowed_with_len::<u32, _>(data, ctx).map(Self)
+    }
+}
+
+/// Reads a field which older peers leave out at the end of a packet.
+pub fn deserialize_trailing<T: Serialize, R: Read>(
+    r: &mut R,
+    ctx: &Context,
+) -> Result<Option<T>, Error> {
+    trailing(r, |r| T::deserialize(r, ctx))
+}
+
+/// Reads a trailing field with `read`, or returns `None` if the data ends before it.
+///
+/// A field which is cut off part way is still an error.
+pub fn trailing<T, R: Read>(
+    r: &mut R,
+    read: impl FnOnce(&mut Chain<&[u8], &mut R>) -> Result<T, Error>,
+) -> Result<Option<T>, Error> {
+    let mut first = [0];
+    loop {
+        match r.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    read(&mut (&first[..]).chain(r)).map(Some)
+}
+
+/// Like [`trailing`], for borrowed data.
+pub fn trailing_borrowed<'a, T>(
+    data: &mut &'a [u8],
+    read: impl FnOnce(&mut &'a [u8]) -> Result<T, Error>,
+) -> Result<Option<T>, Error> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    read(data).map(Some)
+}
+
+/// A field which older peers leave out at the end of a packet.
+///
+/// `None` writes nothing, so only the last fields of a packet may be optional.
+impl<T: Serialize> Serialize for Option<T> {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        match self {
+            Some(value) => value.serialize(w, ctx),
+            None => Ok(()),
+        }
+    }
+
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        deserialize_trailing(r, ctx)
+    }
+}
+
+impl<'a, T: Deserialize<'a>> Deserialize<'a> for Option<T> {
+    fn deserialize_borrowed(data: &mut &'a [u8], ctx: &Context) -> Result<Self, Error> {
+        trailing_borrowed(data, |data| T::deserialize_borrowed(data, ctx))
     }
 }
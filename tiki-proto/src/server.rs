@@ -0,0 +1,661 @@
+//! Server side of a connection, for any number of clients.
+//!
+//! Like [`ClientConnectionState`](crate::ClientConnectionState), this does no
+//! I/O itself: datagrams are passed in along with the address they came from,
+//! and the datagrams to send are polled along with their destination.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::clientbound::{
+    AccessDenied, AccessDeniedCode, AuthAccept, Clientbound, Hello, SrpBytesSB,
+};
+use crate::common::{AuthMechs, V3f};
+use crate::packet::packet_id;
+use crate::peer::{Incoming, Peer};
+use crate::serialize::{Context, Limits, Serialize};
+use crate::serverbound::{Init, Serverbound};
+use crate::srp::SrpServer;
+use crate::transport::{ControlHeader, Frame, PEER_ID_INEXISTENT, PEER_ID_SERVER};
+use crate::{
+    DisconnectReason, MAX_PROTOCOL_VERSION, MAX_SERIALIZATION_VERSION, MIN_PROTOCOL_VERSION,
+    MIN_SERIALIZATION_VERSION,
+};
+
+/// Longest player name clients may use.
+const MAX_NAME_LEN: usize = 20;
+
+/// What the server knows about an account, as returned by the account lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub salt: Vec<u8>,
+    pub verifier: Vec<u8>,
+}
+
+pub struct ServerConfig {
+    /// How long a client may stay silent before it's disconnected.
+    pub timeout: Duration,
+    /// How long to wait after sending anything to a client before sending a ping.
+    pub ping_interval: Duration,
+    /// Sent to clients when they log in.
+    pub map_seed: u64,
+    /// How often clients should send their position, in seconds.
+    pub recommended_send_interval: f32,
+    /// Limits on what clients may make the server decode.
+    pub limits: Limits,
+    /// Maximum number of clients, including ones which haven't logged in yet.
+    ///
+    /// New clients are ignored while the server is full, so they time out.
+    pub max_clients: usize,
+    /// Mechanisms clients may use to enter sudo mode, e.g. to change their password.
+    ///
+    /// Sudo mode isn't handled here, so none are offered by default. A game
+    /// which offers some has to answer the `SrpBytesA`, `SrpBytesM` and
+    /// `FirstSrp` packets of logged in clients itself.
+    pub sudo_auth_mechs: AuthMechs,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            ping_interval: Duration::from_secs(5),
+            map_seed: 0,
+            recommended_send_interval: 0.09,
+            limits: Limits::default(),
+            max_clients: 1000,
+            sudo_auth_mechs: AuthMechs::empty(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerInput<'a> {
+    ReceivedData { from: SocketAddr, data: &'a [u8] },
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub enum ServerOutput {
+    SendData { to: SocketAddr, data: Vec<u8> },
+    Wait,
+}
+
+/// Things that happened to clients which the game may want to react to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A new client has been assigned a peer ID.
+    Connected { peer_id: u16 },
+    /// A client created an account.
+    ///
+    /// The salt and verifier should be stored, and returned by the account
+    /// lookup from now on.
+    Registered {
+        peer_id: u16,
+        name: String,
+        salt: Vec<u8>,
+        verifier: Vec<u8>,
+    },
+    /// A client logged in, and its packets will be handed out from now on.
+    Authenticated { peer_id: u16, name: String },
+    /// A client is gone, and its peer ID may be reused.
+    Disconnected {
+        peer_id: u16,
+        reason: DisconnectReason,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientPhase {
+    AwaitInit,
+    AwaitAuth,
+    AwaitProof,
+    Authenticated,
+}
+
+struct Client {
+    address: SocketAddr,
+    peer: Peer,
+    phase: ClientPhase,
+    context: Context,
+    name: String,
+    auth_mech: AuthMechs,
+    /// The SRP exchange and the client's `A`, while waiting for its proof.
+    srp: Option<(SrpServer, Vec<u8>)>,
+}
+
+type AccountLookup = Box<dyn FnMut(&str) -> Option<Account> + Send>;
+
+pub struct ServerConnectionState {
+    clients: HashMap<u16, Client>,
+    peer_ids: HashMap<SocketAddr, u16>,
+    next_peer_id: u16,
+
+    /// Datagrams left over from clients which have been removed.
+    send_queue: VecDeque<(SocketAddr, Vec<u8>)>,
+    recv_packet_queue: VecDeque<(u16, Serverbound)>,
+    events: VecDeque<ServerEvent>,
+
+    lookup_account: AccountLookup,
+    config: ServerConfig,
+}
+
+impl ServerConnectionState {
+    /// Creates a server which looks up accounts by player name with `lookup_account`.
+    ///
+    /// Players without an account are asked to register.
+    pub fn new(lookup_account: impl FnMut(&str) -> Option<Account> + Send + 'static) -> Self {
+        Self::with_config(lookup_account, ServerConfig::default())
+    }
+
+    pub fn with_config(
+        lookup_account: impl FnMut(&str) -> Option<Account> + Send + 'static,
+        config: ServerConfig,
+    ) -> Self {
+        Self {
+            clients: HashMap::new(),
+            peer_ids: HashMap::new(),
+            next_peer_id: PEER_ID_SERVER + 1,
+
+            send_queue: VecDeque::new(),
+            recv_packet_queue: VecDeque::new(),
+            events: VecDeque::new(),
+
+            lookup_account: Box::new(lookup_account),
+            config,
+        }
+    }
+
+    /// Returns the smoothed round-trip time to a client, once it has been measured.
+    pub fn rtt(&self, peer_id: u16) -> Option<Duration> {
+        self.clients.get(&peer_id)?.peer.rtt()
+    }
+
+    pub fn address(&self, peer_id: u16) -> Option<SocketAddr> {
+        self.clients.get(&peer_id).map(|client| client.address)
+    }
+
+    pub fn poll_event(&mut self) -> Option<ServerEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn submit_input(&mut self, input: ServerInput, now: Instant) -> Result<(), crate::Error> {
+        match input {
+            ServerInput::ReceivedData { from, data } => {
+                self.handle_datagram(from, data, now)?;
+            }
+            ServerInput::TimedOut => {
+                for (&peer_id, client) in &mut self.clients {
+                    let _span = tracing::debug_span!("server", peer_id).entered();
+                    client.peer.handle_timeout(now);
+                }
+
+                self.remove_timed_out_clients(now);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn poll_output(&mut self, now: Instant) -> ServerOutput {
+        self.remove_timed_out_clients(now);
+
+        if let Some((to, data)) = self.send_queue.pop_front() {
+            return ServerOutput::SendData { to, data };
+        }
+
+        for client in self.clients.values_mut() {
+            if client.phase != ClientPhase::AwaitInit {
+                client.peer.ping_if_due(self.config.ping_interval, now);
+            }
+
+            if let Some(data) = client.peer.poll_transmit(now) {
+                return ServerOutput::SendData {
+                    to: client.address,
+                    data,
+                };
+            }
+        }
+
+        ServerOutput::Wait
+    }
+
+    /// Returns the time at which [`ServerInput::TimedOut`] should be submitted,
+    /// if nothing is received before then.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.clients
+            .values()
+            .flat_map(|client| {
+                let timeout = client
+                    .peer
+                    .last_received_at()
+                    .map(|received_at| received_at + self.config.timeout);
+
+                let ping = client
+                    .peer
+                    .next_ping(self.config.ping_interval)
+                    .filter(|_| client.phase != ClientPhase::AwaitInit);
+
+                [client.peer.next_resend(), timeout, ping]
+            })
+            .flatten()
+            .min()
+    }
+
+    /// Queues a packet to be sent to a client.
+    ///
+    /// Packets for clients which are gone are dropped. Fails without sending
+    /// anything if the packet can't be encoded, e.g. because a string in it
+    /// is too long.
+    pub fn send_packet<'a>(
+        &mut self,
+        peer_id: u16,
+        packet: impl Into<Clientbound<'a>>,
+    ) -> Result<(), crate::Error> {
+        let Some(client) = self.clients.get_mut(&peer_id) else {
+            return Ok(());
+        };
+
+        let packet = packet.into();
+
+        let mut data = Vec::new();
+        packet.serialize(&mut data, &client.context)?;
+
+        tracing::debug!(
+            peer_id,
+            id = packet.id(),
+            name = packet.name(),
+            channel = packet.channel(),
+            reliable = packet.is_reliable(),
+            len = data.len(),
+            "sending packet"
+        );
+        tracing::trace!(?packet, "packet dump");
+
+        client
+            .peer
+            .send(packet.channel(), packet.is_reliable(), data)
+    }
+
+    /// Sends a packet as part of logging a client in.
+    ///
+    /// These only fail to encode if the server was given bad data, e.g. an
+    /// account with an oversized verifier, in which case the client times out.
+    fn send_login_packet<'a>(&mut self, peer_id: u16, packet: impl Into<Clientbound<'a>>) {
+        if let Err(e) = self.send_packet(peer_id, packet) {
+            tracing::warn!(peer_id, error = %e, "failed to send packet");
+        }
+    }
+
+    /// Returns the packets received from logged in clients, in the order they arrived.
+    pub fn recv_packets(&mut self) -> impl Iterator<Item = (u16, Serverbound)> + '_ {
+        self.recv_packet_queue.drain(..)
+    }
+
+    /// Tells a client why it's being disconnected, and forgets about it.
+    ///
+    /// The client is forgotten even if the reason is too long to be sent.
+    pub fn kick(
+        &mut self,
+        peer_id: u16,
+        denied: AccessDenied,
+        now: Instant,
+    ) -> Result<(), crate::Error> {
+        let reason = DisconnectReason::from(&denied);
+
+        let result = self.send_packet(peer_id, denied);
+        self.remove_client(peer_id, reason, now);
+
+        result
+    }
+
+    /// Closes the connection to a client without telling it why.
+    pub fn disconnect(&mut self, peer_id: u16, now: Instant) {
+        self.remove_client(peer_id, DisconnectReason::Closed, now);
+    }
+
+    /// Kicks a client without giving a reason beyond the code.
+    fn deny(&mut self, peer_id: u16, code: AccessDeniedCode, now: Instant) {
+        let denied = AccessDenied {
+            code,
+            reason: String::new(),
+            reconnect: false,
+        };
+
+        // Without a reason, the packet always fits.
+        let _ = self.kick(peer_id, denied, now);
+    }
+
+    fn handle_datagram(
+        &mut self,
+        from: SocketAddr,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<(), crate::Error> {
+        let peer_id = match self.peer_ids.get(&from) {
+            Some(&peer_id) => peer_id,
+            None => {
+                let frame = Frame::deserialize(&mut Cursor::new(data), &Context::default())?;
+
+                // Only clients which haven't been assigned an ID yet may
+                // connect, anything else is left over from an old connection.
+                if frame.peer_id != PEER_ID_INEXISTENT {
+                    return Ok(());
+                }
+
+                let Some(peer_id) = self.add_client(from) else {
+                    return Ok(());
+                };
+
+                peer_id
+            }
+        };
+
+        let _span = tracing::debug_span!("server", peer_id).entered();
+
+        let Some(client) = self.clients.get_mut(&peer_id) else {
+            return Ok(());
+        };
+
+        let result = client.peer.receive(data, now);
+
+        while let Some(incoming) = self
+            .clients
+            .get_mut(&peer_id)
+            .and_then(|c| c.peer.poll_incoming())
+        {
+            match incoming {
+                Incoming::Packet(data) => {
+                    // A client which sends garbage is kicked, without affecting the others.
+                    if let Err(e) = self.handle_packet(peer_id, &data, now) {
+                        tracing::info!(error = %e, "client sent a malformed packet");
+                        self.deny(peer_id, AccessDeniedCode::UnexpectedData, now);
+                    }
+                }
+                Incoming::Disco => self.remove_client(peer_id, DisconnectReason::Closed, now),
+                Incoming::SetPeerId(_) => {}
+            }
+        }
+
+        result
+    }
+
+    fn add_client(&mut self, address: SocketAddr) -> Option<u16> {
+        if self.clients.len() >= self.config.max_clients {
+            tracing::warn!(%address, "server is full, ignoring client");
+            return None;
+        }
+
+        let peer_id = self.allocate_peer_id()?;
+
+        let mut peer = Peer::new(PEER_ID_SERVER, &self.config.limits);
+        peer.send_control(ControlHeader::SetPeerId { peer_id }, true);
+
+        self.clients.insert(
+            peer_id,
+            Client {
+                address,
+                peer,
+                phase: ClientPhase::AwaitInit,
+                context: Context {
+                    limits: self.config.limits,
+                    ..Default::default()
+                },
+                name: String::new(),
+                auth_mech: AuthMechs::empty(),
+                srp: None,
+            },
+        );
+        self.peer_ids.insert(address, peer_id);
+
+        tracing::info!(peer_id, %address, "client connected");
+
+        self.events.push_back(ServerEvent::Connected { peer_id });
+
+        Some(peer_id)
+    }
+
+    fn allocate_peer_id(&mut self) -> Option<u16> {
+        for _ in 0..=u16::MAX {
+            let peer_id = self.next_peer_id;
+            self.next_peer_id = peer_id.wrapping_add(1).max(PEER_ID_SERVER + 1);
+
+            if !self.clients.contains_key(&peer_id) {
+                return Some(peer_id);
+            }
+        }
+
+        None
+    }
+
+    /// Forgets about a client, sending out whatever is still queued for it once.
+    fn remove_client(&mut self, peer_id: u16, reason: DisconnectReason, now: Instant) {
+        let Some(mut client) = self.clients.remove(&peer_id) else {
+            return;
+        };
+
+        self.peer_ids.remove(&client.address);
+
+        tracing::info!(peer_id, %reason, "client disconnected");
+
+        // Disco goes out last, so the client handles everything sent before it,
+        // like the reason it's being kicked.
+        let mut remaining = Vec::new();
+        while let Some(data) = client.peer.poll_transmit(now) {
+            remaining.push(data);
+        }
+
+        client.peer.send_control(ControlHeader::Disco, false);
+        remaining.extend(client.peer.poll_transmit(now));
+
+        self.send_queue
+            .extend(remaining.into_iter().map(|data| (client.address, data)));
+
+        self.events
+            .push_back(ServerEvent::Disconnected { peer_id, reason });
+    }
+
+    fn remove_timed_out_clients(&mut self, now: Instant) {
+        let timed_out: Vec<u16> = self
+            .clients
+            .iter_mut()
+            .filter_map(|(&peer_id, client)| {
+                client
+                    .peer
+                    .is_timed_out(self.config.timeout, now)
+                    .then_some(peer_id)
+            })
+            .collect();
+
+        for peer_id in timed_out {
+            self.remove_client(peer_id, DisconnectReason::TimedOut, now);
+        }
+    }
+
+    fn handle_packet(
+        &mut self,
+        peer_id: u16,
+        mut data: &[u8],
+        now: Instant,
+    ) -> Result<(), crate::Error> {
+        let Some(client) = self.clients.get(&peer_id) else {
+            return Ok(());
+        };
+
+        let phase = client.phase;
+
+        let id = packet_id(data);
+        tracing::debug!(
+            id,
+            name = id.and_then(Serverbound::info_by_id).map(|info| info.name),
+            ?phase,
+            len = data.len(),
+            "received packet"
+        );
+
+        let packet = Serverbound::deserialize(&mut data, &client.context)?;
+        tracing::trace!(?packet, "packet dump");
+
+        match packet {
+            Serverbound::Init(ref init) if phase == ClientPhase::AwaitInit => {
+                self.handle_init(peer_id, init, now);
+            }
+            Serverbound::FirstSrp(ref first_srp) if phase == ClientPhase::AwaitAuth => {
+                let client = &self.clients[&peer_id];
+
+                if client.auth_mech != AuthMechs::FIRST_SRP {
+                    self.deny(peer_id, AccessDeniedCode::UnexpectedData, now);
+                    return Ok(());
+                }
+
+                tracing::info!(name = client.name, "client registered");
+
+                self.events.push_back(ServerEvent::Registered {
+                    peer_id,
+                    name: client.name.clone(),
+                    salt: first_srp.salt.clone(),
+                    verifier: first_srp.verifier.clone(),
+                });
+
+                self.accept(peer_id);
+            }
+            Serverbound::SrpBytesA(ref bytes_a) if phase == ClientPhase::AwaitAuth => {
+                let client = &self.clients[&peer_id];
+
+                // Only accounts created with SRP are supported, so the client
+                // must not derive its password from a legacy hash.
+                if client.auth_mech != AuthMechs::SRP || bytes_a.based_on != 1 {
+                    self.deny(peer_id, AccessDeniedCode::UnexpectedData, now);
+                    return Ok(());
+                }
+
+                let name = client.name.clone();
+
+                let Some(account) = (self.lookup_account)(&name) else {
+                    self.deny(peer_id, AccessDeniedCode::ServerFail, now);
+                    return Ok(());
+                };
+
+                let srp = SrpServer::new(&name, &account.salt, &account.verifier);
+                let bytes_b = srp.public_ephemeral();
+
+                let client = self.clients.get_mut(&peer_id).unwrap();
+                client.srp = Some((srp, bytes_a.bytes_a.clone()));
+                client.phase = ClientPhase::AwaitProof;
+
+                self.send_login_packet(
+                    peer_id,
+                    SrpBytesSB {
+                        salt: account.salt,
+                        bytes_b,
+                    },
+                );
+            }
+            Serverbound::SrpBytesM(ref bytes_m) if phase == ClientPhase::AwaitProof => {
+                let client = self.clients.get_mut(&peer_id).unwrap();
+
+                let Some((srp, bytes_a)) = client.srp.take() else {
+                    return Ok(());
+                };
+
+                match srp.verify(&bytes_a, &bytes_m.bytes_m) {
+                    Ok(()) => self.accept(peer_id),
+                    Err(_) => self.deny(peer_id, AccessDeniedCode::WrongPassword, now),
+                }
+            }
+            _ if phase == ClientPhase::Authenticated => {
+                self.recv_packet_queue.push_back((peer_id, packet));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn handle_init(&mut self, peer_id: u16, init: &Init, now: Instant) {
+        let protocol_version = init.max_net_proto_version.min(MAX_PROTOCOL_VERSION);
+        let serialization_version = init
+            .client_max_serialization_ver
+            .min(MAX_SERIALIZATION_VERSION);
+
+        if protocol_version < MIN_PROTOCOL_VERSION
+            || protocol_version < init.min_net_proto_version
+            || serialization_version < MIN_SERIALIZATION_VERSION
+        {
+            self.deny(peer_id, AccessDeniedCode::WrongVersion, now);
+            return;
+        }
+
+        let name = &init.player_name;
+
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            self.deny(peer_id, AccessDeniedCode::WrongName, now);
+            return;
+        }
+
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            self.deny(peer_id, AccessDeniedCode::WrongCharsInName, now);
+            return;
+        }
+
+        let auth_mech = if (self.lookup_account)(name).is_some() {
+            AuthMechs::SRP
+        } else {
+            AuthMechs::FIRST_SRP
+        };
+
+        tracing::debug!(
+            name,
+            protocol_version,
+            serialization_version,
+            auth_mech = ?auth_mech,
+            "client sent init"
+        );
+
+        let client = self.clients.get_mut(&peer_id).unwrap();
+        client.phase = ClientPhase::AwaitAuth;
+        client.name = name.clone();
+        client.auth_mech = auth_mech;
+        client.context.protocol_version = protocol_version;
+        client.context.serialization_version = serialization_version;
+
+        self.send_login_packet(
+            peer_id,
+            Hello {
+                serialization_version,
+                compression_mode: 0,
+                protocol_version,
+                auth_mechs: auth_mech,
+                legacy_name: name.clone(),
+            },
+        );
+    }
+
+    /// Lets a client in after it has logged in or registered.
+    fn accept(&mut self, peer_id: u16) {
+        let client = self.clients.get_mut(&peer_id).unwrap();
+        client.phase = ClientPhase::Authenticated;
+
+        let name = client.name.clone();
+
+        tracing::info!(name, "client authenticated");
+
+        self.send_login_packet(
+            peer_id,
+            AuthAccept {
+                player_pos: V3f::default(),
+                map_seed: self.config.map_seed,
+                recommended_send_interval: self.config.recommended_send_interval,
+                sudo_auth_mechs: self.config.sudo_auth_mechs,
+            },
+        );
+
+        self.events
+            .push_back(ServerEvent::Authenticated { peer_id, name });
+    }
+}
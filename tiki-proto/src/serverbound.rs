@@ -1,83 +1,122 @@
 use tiki_macros::Serialize;
 
-use crate::Error;
-
+/// Packets sent by the client.
+///
+/// The channels match the server's own table, so that packets which depend
+/// on each other's order end up on the same channel.
 #[tiki_macros::packet]
 #[derive(Debug)]
 pub enum Serverbound {
     #[id = 0x00]
+    #[reliable]
     Hello(Hello),
 
+    /// Unreliable, since it's resent until the server answers.
     #[id = 0x02]
+    #[channel = 1]
     Init(Init),
 
     #[id = 0x11]
+    #[channel = 1]
+    #[reliable]
     Init2(Init2),
 
     #[id = 0x17]
+    #[reliable]
     ModChannelJoin(ModChannelJoin),
 
     #[id = 0x18]
+    #[reliable]
     ModChannelLeave(ModChannelLeave),
 
     #[id = 0x19]
+    #[reliable]
     ModChannelMsg(ModChannelMsg),
 
+    /// Unreliable, since a lost position update is superseded by the next one anyway.
     #[id = 0x23]
     PlayerPos(PlayerPos),
 
     #[id = 0x24]
+    #[channel = 2]
+    #[reliable]
     GotBlocks(GotBlocks),
 
     #[id = 0x25]
+    #[channel = 2]
+    #[reliable]
     DeletedBlocks(DeletedBlocks),
 
     #[id = 0x31]
+    #[reliable]
     InventoryAction(InventoryAction),
 
     #[id = 0x32]
+    #[reliable]
     ChatMessage(ChatMessage),
 
     #[id = 0x35]
+    #[reliable]
     Damage(Damage),
 
     #[id = 0x37]
+    #[reliable]
     PlayerItem(PlayerItem),
 
     #[id = 0x38]
+    #[reliable]
     Respawn(Respawn),
 
     #[id = 0x39]
+    #[reliable]
     Interact(Interact),
 
     #[id = 0x3A]
+    #[channel = 2]
+    #[reliable]
     RemovedSounds(RemovedSounds),
 
     #[id = 0x3B]
+    #[reliable]
     NodeMetaFields(NodeMetaFields),
 
     #[id = 0x3C]
+    #[reliable]
     InventoryFields(InventoryFields),
 
     #[id = 0x40]
+    #[channel = 1]
+    #[reliable]
     RequestMedia(RequestMedia),
 
     #[id = 0x41]
+    #[channel = 2]
+    #[reliable]
     HaveMedia(HaveMedia),
 
     #[id = 0x43]
+    #[channel = 1]
+    #[reliable]
     ClientReady(ClientReady),
 
     #[id = 0x50]
+    #[channel = 1]
+    #[reliable]
     FirstSrp(FirstSrp),
 
     #[id = 0x51]
+    #[channel = 1]
+    #[reliable]
     SrpBytesA(SrpBytesA),
 
     #[id = 0x52]
+    #[channel = 1]
+    #[reliable]
     SrpBytesM(SrpBytesM),
 
     #[id = 0x53]
+    #[channel = 1]
+    #[reliable]
     UpdateClientInfo(UpdateClientInfo),
 }
 
@@ -144,22 +183,43 @@ pub struct NodeMetaFields {}
 pub struct InventoryFields {}
 
 #[derive(Serialize, Debug)]
-pub struct RequestMedia {}
+pub struct RequestMedia {
+    pub files: Vec<String>,
+}
 
 #[derive(Serialize, Debug)]
 pub struct HaveMedia {}
 
 #[derive(Serialize, Debug)]
-pub struct ClientReady {}
+pub struct ClientReady {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+    pub reserved: u8,
+    pub full_version: String,
+    pub formspec_version: u16,
+}
 
 #[derive(Serialize, Debug)]
-pub struct FirstSrp {}
+pub struct FirstSrp {
+    pub salt: Vec<u8>,
+    pub verifier: Vec<u8>,
+    /// 1 if the password is empty.
+    pub is_empty: u8,
+}
 
 #[derive(Serialize, Debug)]
-pub struct SrpBytesA {}
+pub struct SrpBytesA {
+    pub bytes_a: Vec<u8>,
+    /// 1 if the server's verifier was derived from the password itself,
+    /// 0 if it was derived from the legacy password hash.
+    pub based_on: u8,
+}
 
 #[derive(Serialize, Debug)]
-pub struct SrpBytesM {}
+pub struct SrpBytesM {
+    pub bytes_m: Vec<u8>,
+}
 
 #[derive(Serialize, Debug)]
 pub struct UpdateClientInfo {}
@@ -0,0 +1,325 @@
+//! SRP-6a, as implemented by the csrp fork used by Minetest servers.
+//!
+//! The group is the 2048-bit one from RFC 5054 and the hash is SHA-256.
+//! Unlike plain SRP, the verifier is derived from the lowercased player
+//! name, while the proof uses the name as typed.
+
+use std::sync::LazyLock;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use num_bigint::BigUint;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+const N_HEX: &[u8] = b"\
+    AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050A37329CBB4\
+    A099ED8193E0757767A13DD52312AB4B03310DCD7F48A9DA04FD50E8083969EDB767B0CF60\
+    95179A163AB3661A05FBD5FAAAE82918A9962F0B93B855F97993EC975EEAA80D740ADBF4FF\
+    747359D041D5C33EA71D281E446B14773BCA97B43A23FB801676BD207A436C6481F1D2B907\
+    8717461A5B9D32E688F87748544523B524B0D57D5EA77A2775D2ECFA032CFBDBF52FB37861\
+    60279004E57AE6AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8E9DB\
+    FBB694B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73";
+
+static N: LazyLock<BigUint> = LazyLock::new(|| BigUint::parse_bytes(N_HEX, 16).unwrap());
+static G: LazyLock<BigUint> = LazyLock::new(|| BigUint::from(2u32));
+
+/// The multiplier parameter, `k = H(N | PAD(g))`.
+static K: LazyLock<BigUint> =
+    LazyLock::new(|| BigUint::from_bytes_be(&hash(&[&N.to_bytes_be(), &pad(&G)])));
+
+/// Length of the salts generated by [`generate_salt`].
+pub const SALT_LEN: usize = 16;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum SrpError {
+    #[error("peer sent an invalid public ephemeral value")]
+    InvalidPublicValue,
+
+    #[error("client proof doesn't match, the password is wrong")]
+    InvalidProof,
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Left-pads a number to the length of `N`.
+fn pad(n: &BigUint) -> Vec<u8> {
+    let len = N.to_bytes_be().len();
+    let bytes = n.to_bytes_be();
+
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+/// Computes `x = H(s | H(I | ":" | P))`.
+fn calculate_x(name: &str, password: &str, salt: &[u8]) -> BigUint {
+    let inner = hash(&[name.as_bytes(), b":", password.as_bytes()]);
+    BigUint::from_bytes_be(&hash(&[salt, &inner]))
+}
+
+/// Computes the client's proof, `M = H(H(N) xor H(g) | H(I) | s | A | B | K)`.
+fn calculate_m(name: &str, salt: &[u8], a_pub: &BigUint, b_pub: &BigUint, key: &[u8]) -> Vec<u8> {
+    let h_n = hash(&[&N.to_bytes_be()]);
+    let h_g = hash(&[&G.to_bytes_be()]);
+    let h_xor: Vec<u8> = h_n.iter().zip(h_g).map(|(n, g)| n ^ g).collect();
+
+    let h_i = hash(&[name.as_bytes()]);
+
+    hash(&[
+        &h_xor,
+        &h_i,
+        salt,
+        &a_pub.to_bytes_be(),
+        &b_pub.to_bytes_be(),
+        key,
+    ])
+    .to_vec()
+}
+
+pub fn generate_salt() -> [u8; SALT_LEN] {
+    rand::random()
+}
+
+/// Derives the verifier the server stores for an account.
+pub fn generate_verifier(name: &str, password: &str, salt: &[u8]) -> Vec<u8> {
+    let x = calculate_x(&name.to_lowercase(), password, salt);
+    G.modpow(&x, &N).to_bytes_be()
+}
+
+/// Hashes a password the way servers stored them before SRP.
+///
+/// Accounts which still have such a hash log in with SRP, using the hash
+/// in place of the password.
+pub fn legacy_password_hash(name: &str, password: &str) -> String {
+    if password.is_empty() {
+        return String::new();
+    }
+
+    let digest = Sha1::new()
+        .chain_update(name)
+        .chain_update(password)
+        .finalize();
+
+    BASE64_STANDARD.encode(digest)
+}
+
+/// Client side of an SRP exchange.
+pub struct SrpClient {
+    name: String,
+    password: String,
+    a: BigUint,
+    a_pub: BigUint,
+}
+
+impl SrpClient {
+    pub fn new(name: &str, password: &str) -> Self {
+        Self::with_secret(name, password, &rand::random::<[u8; 32]>())
+    }
+
+    fn with_secret(name: &str, password: &str, a: &[u8]) -> Self {
+        let a = BigUint::from_bytes_be(a);
+        let a_pub = G.modpow(&a, &N);
+
+        Self {
+            name: name.to_owned(),
+            password: password.to_owned(),
+            a,
+            a_pub,
+        }
+    }
+
+    /// Returns `A`, which is sent to the server to start the exchange.
+    pub fn public_ephemeral(&self) -> Vec<u8> {
+        self.a_pub.to_bytes_be()
+    }
+
+    /// Computes the proof `M` from the salt and `B` sent by the server.
+    ///
+    /// Minetest servers don't prove that they know the verifier: there is no
+    /// `H(A | M | K)` in the protocol, so the exchange ends with this proof.
+    pub fn process_challenge(&self, salt: &[u8], b_pub: &[u8]) -> Result<Vec<u8>, SrpError> {
+        let b_pub = BigUint::from_bytes_be(b_pub);
+
+        if (&b_pub % &*N) == BigUint::ZERO {
+            return Err(SrpError::InvalidPublicValue);
+        }
+
+        let u = BigUint::from_bytes_be(&hash(&[&pad(&self.a_pub), &pad(&b_pub)]));
+
+        if u == BigUint::ZERO {
+            return Err(SrpError::InvalidPublicValue);
+        }
+
+        let x = calculate_x(&self.name.to_lowercase(), &self.password, salt);
+
+        // S = (B - k * g^x) ^ (a + u * x) mod N
+        let kv = (&*K * G.modpow(&x, &N)) % &*N;
+        let base = (&b_pub % &*N + &*N - kv) % &*N;
+        let s = base.modpow(&(&self.a + &u * &x), &N);
+
+        let key = hash(&[&s.to_bytes_be()]);
+
+        Ok(calculate_m(&self.name, salt, &self.a_pub, &b_pub, &key))
+    }
+}
+
+/// Server side of an SRP exchange.
+pub struct SrpServer {
+    name: String,
+    salt: Vec<u8>,
+    verifier: BigUint,
+    b: BigUint,
+    b_pub: BigUint,
+}
+
+impl SrpServer {
+    /// Starts an exchange for an account with the given salt and verifier.
+    ///
+    /// `name` must be the name as typed by the client, since it's part of the proof.
+    pub fn new(name: &str, salt: &[u8], verifier: &[u8]) -> Self {
+        Self::with_secret(name, salt, verifier, &rand::random::<[u8; 32]>())
+    }
+
+    fn with_secret(name: &str, salt: &[u8], verifier: &[u8], b: &[u8]) -> Self {
+        let verifier = BigUint::from_bytes_be(verifier);
+        let b = BigUint::from_bytes_be(b);
+
+        // B = k * v + g^b mod N
+        let b_pub = (&*K * &verifier + G.modpow(&b, &N)) % &*N;
+
+        Self {
+            name: name.to_owned(),
+            salt: salt.to_vec(),
+            verifier,
+            b,
+            b_pub,
+        }
+    }
+
+    /// Returns `B`, which is sent to the client along with the salt.
+    pub fn public_ephemeral(&self) -> Vec<u8> {
+        self.b_pub.to_bytes_be()
+    }
+
+    /// Checks the proof `M` the client computed from `A`.
+    pub fn verify(&self, a_pub: &[u8], proof: &[u8]) -> Result<(), SrpError> {
+        let a_pub = BigUint::from_bytes_be(a_pub);
+
+        if (&a_pub % &*N) == BigUint::ZERO {
+            return Err(SrpError::InvalidPublicValue);
+        }
+
+        let u = BigUint::from_bytes_be(&hash(&[&pad(&a_pub), &pad(&self.b_pub)]));
+
+        if u == BigUint::ZERO {
+            return Err(SrpError::InvalidPublicValue);
+        }
+
+        // S = (A * v^u) ^ b mod N
+        let base = (&a_pub * self.verifier.modpow(&u, &N)) % &*N;
+        let s = base.modpow(&self.b, &N);
+
+        let key = hash(&[&s.to_bytes_be()]);
+
+        let expected = calculate_m(&self.name, &self.salt, &a_pub, &self.b_pub, &key);
+
+        if !bool::from(expected.ct_eq(proof)) {
+            return Err(SrpError::InvalidProof);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Computed with a transcription of Minetest's csrp (SHA-256, 2048-bit
+    // group), independent of the code above. The secrets and salt are the
+    // ones from RFC 5054, appendix B.
+    const NAME: &str = "Alice";
+    const PASSWORD: &str = "password123";
+    const SALT: &str = "beb25379d1a8581eb5a727673a2441ee";
+    const A: &str = "60975527035cf2ad1989806f0407210bc81edc04e2762a56afd529ddda2d4393";
+    const B: &str = "e487cb59d31ac550471e81f00f6928e01dda08e974a004f49e61f5d105284d20";
+
+    const VERIFIER: &str = "\
+        400272a61e185e23784e28a16a149dc60a3790fd45856f79a7070c44f7da1ca22f711cd5bc\
+        3592171a875c7812472916de2dcfafc22f7dead8f578f1970547936f9eec686bb3df66ff57\
+        f724f6b907e83530812b4ffdbf614153e9fbfed4fc6d972da70bb23f6ccd36ad08b72567fe\
+        6bcd2bacb713f2cdb9dc8f81f897f489bb393067d66237a3e061902e72096d5ac1cd1d06c1\
+        cd648f7e56da5ec6e0094c1b448c5d63ad2addec1e3d9a3aa7118a0410e53434ddbffc60ee\
+        f5b82548bda5a2f513209484d3221982ca74668a4d37330cc9cfe3b10f0db368293e43026e\
+        3a01440ac732bc1cfb983b512d10296f6951ec5e567329af8e58d7c21ea6c778b0bd";
+
+    const A_PUB: &str = "\
+        4b700f8d48e69c9aae40c684ac7c7c03121e2b7602eb4c3514804ccada0ed4019193a351ec\
+        c65a6f854ede91eb096e721b22d701c7adc64e9cedacd75f2e26bb2f5e45dd53dc8dbeafff\
+        e82aa49fca0573444691212537a73cf80e25039258205a7edf4749b30adaf25877c62fcd09\
+        d6613598bcd4baf2a9727a53706a278148992b2abb23ad5d512d269e16ca11bc0895b5a3b5\
+        ec4721cde40a8c39c796e94f0be86dbbeb33da7037018983921aba3f5053195d5ac1da4e56\
+        7e3c0e75d9e0609f92e850657b2be4771f415b9cacc5c1ecedc30133bf6474f5022c6519d7\
+        80760ca4d8d3b966b034bd73877c1b3b33f474b9c3c5299a1968f3e6cd3bfe84445a";
+
+    const B_PUB: &str = "\
+        410813e3063f3b4532f2d36413749f39c26c5ceeb1346d3995003c74544c30cba318f98128\
+        1607ae68dbdc3bee9f0544ada6b13d8ac33217b670973152cf03ef03797615e81dd305342c\
+        2e3bb035321d1fd717952e702b09682102d0a5aa25dcee01784a32b0684f75626ca3bf8aec\
+        874f2dc11f8926944b06f9948e8ad7649025a58cd9dccdb6b210de00e2283e72baaf93a39b\
+        0417dfd1888f841f43d7d41c75b58f654ccb2e8b9c875c42edc34fd3796200312f2abd19b7\
+        e2c54b5702cd1a7f4d79fdf73bc418c96466ba122d45474ab6db553417715617f6c3b4a876\
+        4279f086acc655e396f85812c90f6f932ce0586168c5deccc9f8beb6891ad13f7caf";
+
+    const PROOF: &str = "ba9f2041c33823c90e5b40b4e1634b1b4ed2d96c671d34ffff84b1360630e97f";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derives_verifiers_like_csrp() {
+        // The verifier is derived from the lowercased name.
+        let verifier = generate_verifier(NAME, PASSWORD, &from_hex(SALT));
+        assert_eq!(verifier, from_hex(VERIFIER));
+        assert_eq!(
+            generate_verifier("alice", PASSWORD, &from_hex(SALT)),
+            verifier
+        );
+    }
+
+    #[test]
+    fn computes_proofs_like_csrp() {
+        let salt = from_hex(SALT);
+
+        let client = SrpClient::with_secret(NAME, PASSWORD, &from_hex(A));
+        assert_eq!(client.public_ephemeral(), from_hex(A_PUB));
+
+        let server = SrpServer::with_secret(NAME, &salt, &from_hex(VERIFIER), &from_hex(B));
+        assert_eq!(server.public_ephemeral(), from_hex(B_PUB));
+
+        let proof = client.process_challenge(&salt, &from_hex(B_PUB)).unwrap();
+        assert_eq!(proof, from_hex(PROOF));
+        assert_eq!(server.verify(&from_hex(A_PUB), &proof), Ok(()));
+
+        // The proof uses the name as typed.
+        let lowercase = SrpClient::with_secret("alice", PASSWORD, &from_hex(A));
+        let proof = lowercase
+            .process_challenge(&salt, &from_hex(B_PUB))
+            .unwrap();
+        assert_eq!(
+            server.verify(&from_hex(A_PUB), &proof),
+            Err(SrpError::InvalidProof)
+        );
+    }
+}
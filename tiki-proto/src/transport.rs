@@ -2,11 +2,34 @@ use std::io::{Read, Write};
 
 use tiki_macros::Serialize;
 
-use crate::serialize::Serialize;
+use crate::serialize::{Context, Serialize};
 use crate::Error;
 
 const PROTOCOL_ID: u32 = 0x4F457403;
 
+/// Peer ID used by clients until the server has assigned them one.
+pub const PEER_ID_INEXISTENT: u16 = 0;
+
+/// Peer ID the server puts into the frames it sends.
+pub const PEER_ID_SERVER: u16 = 1;
+
+/// Number of channels every peer has.
+pub const CHANNEL_COUNT: usize = 3;
+
+/// Maximum size of a datagram, including all headers.
+///
+/// Packets which don't fit into a single frame are split into chunks.
+pub const MAX_FRAME_SIZE: usize = 512;
+
+/// Size of the protocol ID, peer ID and channel fields.
+pub const BASE_HEADER_SIZE: usize = 7;
+
+/// Size of the reliable frame header, including its type byte.
+pub const RELIABLE_HEADER_SIZE: usize = 3;
+
+/// Size of the split frame header, including its type byte.
+pub const SPLIT_HEADER_SIZE: usize = 7;
+
 #[derive(thiserror::Error, Debug)]
 pub enum TransportError {
     #[error("unknown protocol ID: {0:08X}")]
@@ -15,8 +38,14 @@ pub enum TransportError {
     #[error("unknown frame type: {0}")]
     UnknownFrameType(u8),
 
-    #[error("unknown control frame type: {0}")]
-    UnknownControlType(u8),
+    #[error("invalid channel: {0}")]
+    InvalidChannel(u8),
+
+    #[error("invalid split chunk {chunk_number} of {chunk_count}")]
+    InvalidSplitChunk { chunk_number: u16, chunk_count: u16 },
+
+    #[error("split packets exceed buffer limit of {0} bytes")]
+    SplitBufferFull(usize),
 }
 
 #[derive(Debug)]
@@ -28,55 +57,45 @@ pub struct Frame {
 }
 
 impl Serialize for Frame {
-    fn serialize<W: Write>(&self, w: &mut W) {
-        PROTOCOL_ID.serialize(w);
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        PROTOCOL_ID.serialize(w, ctx)?;
 
-        self.peer_id.serialize(w);
-        self.channel.serialize(w);
+        self.peer_id.serialize(w, ctx)?;
+        self.channel.serialize(w, ctx)?;
 
         if let Reliability::Reliable { seqnum } = self.reliability {
-            3u8.serialize(w);
-            seqnum.serialize(w);
+            3u8.serialize(w, ctx)?;
+            seqnum.serialize(w, ctx)?;
         }
 
-        match self.ty {
-            FrameType::Control(ref control) => {
-                0u8.serialize(w);
-                control.serialize(w);
-            }
-            FrameType::Original => 1u8.serialize(w),
-            FrameType::Split(ref split) => {
-                2u8.serialize(w);
-                split.serialize(w);
-            }
-        }
+        self.ty.serialize(w, ctx)
     }
 
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error> {
-        let protocol_id = u32::deserialize(r)?;
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let protocol_id = u32::deserialize(r, ctx)?;
 
         if protocol_id != PROTOCOL_ID {
             Err(TransportError::UnknownProtocolId(protocol_id))?
         }
 
-        let peer_id = u16::deserialize(r)?;
-        let channel = u8::deserialize(r)?;
-        let mut ty = u8::deserialize(r)?;
+        let peer_id = u16::deserialize(r, ctx)?;
+        let channel = u8::deserialize(r, ctx)?;
+
+        if channel as usize >= CHANNEL_COUNT {
+            Err(TransportError::InvalidChannel(channel))?
+        }
+
+        let mut ty = u8::deserialize(r, ctx)?;
 
         let reliability = if ty == 3 {
-            let seqnum = u16::deserialize(r)?;
-            ty = u8::deserialize(r)?;
+            let seqnum = u16::deserialize(r, ctx)?;
+            ty = u8::deserialize(r, ctx)?;
             Reliability::Reliable { seqnum }
         } else {
             Reliability::Unreliable
         };
 
-        let ty = match ty {
-            0 => FrameType::Control(ControlHeader::deserialize(r)?),
-            1 => FrameType::Original,
-            2 => FrameType::Split(SplitHeader::deserialize(r)?),
-            _ => Err(TransportError::UnknownFrameType(ty))?,
-        };
+        let ty = FrameType::deserialize_with_type(ty, r, ctx)?;
 
         Ok(Self {
             peer_id,
@@ -100,47 +119,48 @@ pub enum FrameType {
     Split(SplitHeader),
 }
 
-#[derive(Debug)]
-pub enum ControlHeader {
-    Ack { seqnum: u16 },
-    SetPeerId { peer_id: u16 },
-    Ping,
-    Disco,
+impl FrameType {
+    /// Reads the rest of the frame type header, given the type byte.
+    fn deserialize_with_type<R: Read>(ty: u8, r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        Ok(match ty {
+            0 => FrameType::Control(ControlHeader::deserialize(r, ctx)?),
+            1 => FrameType::Original,
+            2 => FrameType::Split(SplitHeader::deserialize(r, ctx)?),
+            _ => Err(TransportError::UnknownFrameType(ty))?,
+        })
+    }
 }
 
-impl Serialize for ControlHeader {
-    fn serialize<W: Write>(&self, w: &mut W) {
+impl Serialize for FrameType {
+    fn serialize<W: Write>(&self, w: &mut W, ctx: &Context) -> Result<(), Error> {
         match self {
-            ControlHeader::Ack { seqnum } => {
-                0u8.serialize(w);
-                seqnum.serialize(w);
+            FrameType::Control(control) => {
+                0u8.serialize(w, ctx)?;
+                control.serialize(w, ctx)
             }
-            ControlHeader::SetPeerId { peer_id } => {
-                1u8.serialize(w);
-                peer_id.serialize(w);
+            FrameType::Original => 1u8.serialize(w, ctx),
+            FrameType::Split(split) => {
+                2u8.serialize(w, ctx)?;
+                split.serialize(w, ctx)
             }
-            ControlHeader::Ping => 2u8.serialize(w),
-            ControlHeader::Disco => 3u8.serialize(w),
         }
     }
 
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, Error> {
-        let ty = u8::deserialize(r)?;
-
-        Ok(match ty {
-            0 => ControlHeader::Ack {
-                seqnum: u16::deserialize(r)?,
-            },
-            1 => ControlHeader::SetPeerId {
-                peer_id: u16::deserialize(r)?,
-            },
-            2 => ControlHeader::Ping,
-            3 => ControlHeader::Disco,
-            _ => Err(TransportError::UnknownControlType(ty))?,
-        })
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, Error> {
+        let ty = u8::deserialize(r, ctx)?;
+        Self::deserialize_with_type(ty, r, ctx)
     }
 }
 
+#[derive(Debug, Serialize)]
+#[repr(u8)]
+pub enum ControlHeader {
+    Ack { seqnum: u16 } = 0,
+    SetPeerId { peer_id: u16 } = 1,
+    Ping = 2,
+    Disco = 3,
+}
+
 #[derive(Debug, Serialize)]
 pub struct SplitHeader {
     pub seqnum: u16,
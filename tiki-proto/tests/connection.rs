@@ -0,0 +1,653 @@
+mod support;
+
+use std::io::Cursor;
+use std::time::Duration;
+
+use tiki_proto::clientbound::{
+    AccessDenied, AccessDeniedCode, AccessDeniedLegacy, Clientbound, Hello, Hp, TimeOfDay,
+};
+use tiki_proto::common::AuthMechs;
+use tiki_proto::serialize::{Context, Serialize};
+use tiki_proto::server;
+use tiki_proto::server::ServerEvent;
+use tiki_proto::serverbound::{Init2, Serverbound};
+use tiki_proto::srp::{SrpClient, SrpServer};
+use tiki_proto::transport::{ControlHeader, Frame, FrameType, MAX_FRAME_SIZE};
+use tiki_proto::{
+    AuthError, ClientConfig, ClientConnectionState, Credentials, DisconnectReason, Error, Event,
+    Input,
+};
+
+use support::{game_script, Account, FakeServer, Faults, GameContent, Network, Sudo};
+
+const NAME: &str = "singleplayer";
+const PASSWORD: &str = "hunter2";
+
+fn client(password: &str, config: ClientConfig) -> ClientConnectionState {
+    ClientConnectionState::with_config(
+        Credentials {
+            name: NAME.to_owned(),
+            password: password.to_owned(),
+        },
+        config,
+    )
+}
+
+fn content() -> GameContent {
+    GameContent {
+        node_defs: vec![1, 2, 3],
+        media: vec![
+            ("default_stone.png".to_owned(), vec![0; 100]),
+            ("default_dirt.png".to_owned(), vec![1; 100]),
+        ],
+    }
+}
+
+/// Whether the client has joined, and the server knows it.
+fn in_game(network: &Network) -> bool {
+    network.has_event(&Event::Joined)
+        && network
+            .server
+            .received
+            .iter()
+            .any(|packet| matches!(packet, Serverbound::ClientReady(_)))
+}
+
+/// Returns a short name for each kind of packet the client sent once logged in, dropping repeats.
+fn sent_kinds(server: &FakeServer) -> Vec<&'static str> {
+    let mut kinds: Vec<_> = server
+        .received
+        .iter()
+        .map(|packet| match packet {
+            Serverbound::Init2(_) => "Init2",
+            Serverbound::RequestMedia(_) => "RequestMedia",
+            Serverbound::ClientReady(_) => "ClientReady",
+            _ => "other",
+        })
+        .collect();
+
+    kinds.dedup();
+    kinds
+}
+
+#[test]
+fn logs_in_and_joins() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+
+    assert_eq!(
+        sent_kinds(&network.server),
+        ["Init2", "RequestMedia", "ClientReady"]
+    );
+
+    let peer_id = network.server.peer_id.unwrap();
+    assert_eq!(
+        network.server.events,
+        [
+            ServerEvent::Connected { peer_id },
+            ServerEvent::Authenticated {
+                peer_id,
+                name: NAME.to_owned()
+            }
+        ]
+    );
+
+    assert_eq!(
+        network.events,
+        [
+            Event::ItemDefsReceived,
+            Event::NodeDefsReceived,
+            Event::MediaProgress {
+                received: 0,
+                total: 2
+            },
+            Event::MediaProgress {
+                received: 1,
+                total: 2
+            },
+            Event::MediaProgress {
+                received: 2,
+                total: 2
+            },
+            Event::Joined,
+        ]
+    );
+
+    assert_eq!(network.client.context(), &support::CONTEXT);
+    assert!(network.client.rtt().is_some());
+}
+
+#[test]
+fn measures_rtt() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+    network.delay = Duration::from_millis(50);
+
+    assert!(network.run_until(Duration::from_secs(10), in_game));
+
+    // Acks are sent right away, so the round trip only takes as long as the
+    // network, give or take a step of the simulated clock.
+    let rtt = network.client.rtt().unwrap();
+    assert!(
+        (Duration::from_millis(100)..=Duration::from_millis(110)).contains(&rtt),
+        "{rtt:?}"
+    );
+}
+
+#[test]
+fn pings_while_idle() {
+    let config = ClientConfig {
+        ping_interval: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, config), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+    network.run_until(Duration::from_secs(1), |_| false);
+    let joined = network.server_datagrams.len();
+
+    network.run_until(Duration::from_secs(3), |_| false);
+
+    let pings = network.server_datagrams[joined..]
+        .iter()
+        .filter(|data| {
+            let frame = Frame::deserialize(&mut Cursor::new(&data[..]), &Context::default());
+            matches!(frame.unwrap().ty, FrameType::Control(ControlHeader::Ping))
+        })
+        .count();
+    assert!((2..=3).contains(&pings), "{pings} pings");
+
+    assert_eq!(network.disconnect_reason, None);
+    assert!(!network
+        .server
+        .events
+        .iter()
+        .any(|event| matches!(event, ServerEvent::Disconnected { .. })));
+}
+
+#[test]
+fn registers_unknown_account() {
+    let config = ClientConfig {
+        register: true,
+        ..Default::default()
+    };
+    let server = FakeServer::new(None, game_script(content()));
+    let mut network = Network::new(client(PASSWORD, config), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+
+    assert!(network.server.events.iter().any(|event| matches!(
+        event,
+        ServerEvent::Registered { name, .. } if name == NAME
+    )));
+    assert!(network.server.is_authenticated());
+}
+
+#[test]
+fn refuses_to_register_by_default() {
+    let server = FakeServer::new(None, game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert_eq!(
+        network.run_until_disconnected(Duration::from_secs(5)),
+        Some(DisconnectReason::AuthFailed(
+            AuthError::RegistrationDisabled
+        ))
+    );
+}
+
+#[test]
+fn reports_wrong_password() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client("hunter3", ClientConfig::default()), server);
+
+    assert_eq!(
+        network.run_until_disconnected(Duration::from_secs(5)),
+        Some(DisconnectReason::AuthFailed(AuthError::WrongPassword))
+    );
+}
+
+/// Joins the game, then has the server kick the client, returning why the client thinks it was.
+fn kicked_with(code: AccessDeniedCode, reason: &str, reconnect: bool) -> Option<DisconnectReason> {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+
+    let denied = AccessDenied {
+        code,
+        reason: reason.to_owned(),
+        reconnect,
+    };
+    network.server.kick(denied, network.now);
+
+    network.run_until_disconnected(Duration::from_secs(1))
+}
+
+#[test]
+fn reports_shutdown() {
+    let reason = kicked_with(AccessDeniedCode::Shutdown, "maintenance", false).unwrap();
+
+    assert_eq!(
+        reason,
+        DisconnectReason::Shutdown {
+            message: "maintenance".to_owned(),
+            reconnect: false
+        }
+    );
+    assert!(!reason.reconnect_suggested());
+}
+
+#[test]
+fn reports_crash_with_reconnect() {
+    let reason = kicked_with(AccessDeniedCode::Crash, "lua error", true).unwrap();
+
+    assert_eq!(
+        reason,
+        DisconnectReason::Crash {
+            message: "lua error".to_owned(),
+            reconnect: true
+        }
+    );
+    assert!(reason.reconnect_suggested());
+}
+
+#[test]
+fn reports_unknown_access_denied_code() {
+    assert_eq!(
+        kicked_with(AccessDeniedCode::Unknown(200), "from the future", false),
+        Some(DisconnectReason::AccessDenied {
+            code: AccessDeniedCode::Unknown(200),
+            message: "from the future".to_owned()
+        })
+    );
+}
+
+#[test]
+fn reports_legacy_access_denied() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+
+    network.server.send(AccessDeniedLegacy {
+        reason: "Kicked: ✗".to_owned(),
+    });
+
+    assert_eq!(
+        network.run_until_disconnected(Duration::from_secs(1)),
+        Some(DisconnectReason::Custom("Kicked: ✗".to_owned()))
+    );
+}
+
+#[test]
+fn refuses_unsupported_protocol_versions() {
+    for protocol_version in [
+        tiki_proto::MIN_PROTOCOL_VERSION - 1,
+        tiki_proto::MAX_PROTOCOL_VERSION + 1,
+    ] {
+        let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+        let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+        // Say hello before the server does, as soon as the client has a peer ID.
+        assert!(network.run_until(Duration::from_secs(5), |network| {
+            network.server.peer_id.is_some()
+        }));
+        network.server.send(Hello {
+            serialization_version: tiki_proto::MAX_SERIALIZATION_VERSION,
+            compression_mode: 0,
+            protocol_version,
+            auth_mechs: AuthMechs::SRP,
+            legacy_name: NAME.to_owned(),
+        });
+
+        assert_eq!(
+            network.run_until_disconnected(Duration::from_secs(5)),
+            Some(DisconnectReason::VersionMismatch),
+            "protocol version {protocol_version}"
+        );
+    }
+}
+
+#[test]
+fn closes_on_disco() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+
+    network.server.disconnect(network.now);
+
+    assert_eq!(
+        network.run_until_disconnected(Duration::from_secs(1)),
+        Some(DisconnectReason::Closed)
+    );
+}
+
+#[test]
+fn handles_packets_after_an_unknown_one() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+    network.run_until(Duration::from_secs(1), |_| false);
+    let received = network.packets.len();
+
+    network.server.send(TimeOfDay {});
+    network.server.send(Hp {});
+    let mut unknown = network.server.poll_transmit(network.now).unwrap();
+    let hp = network.server.poll_transmit(network.now).unwrap();
+
+    // Give the first packet an id which doesn't exist.
+    let mut r = Cursor::new(&unknown[..]);
+    Frame::deserialize(&mut r, &Context::default()).unwrap();
+    let payload = r.position() as usize;
+    unknown[payload..payload + 2].copy_from_slice(&[0xff, 0xff]);
+
+    // The packet after it arrives first, so both are handled at once.
+    network
+        .client
+        .submit_input(Input::ReceivedData(&hp), network.now)
+        .unwrap();
+    assert!(matches!(
+        network
+            .client
+            .submit_input(Input::ReceivedData(&unknown), network.now),
+        Err(Error::UnknownPacket(0xffff))
+    ));
+
+    let packets: Vec<_> = network.client.recv_packets().collect();
+    assert_eq!(packets.len(), 1);
+    assert!(matches!(packets[0].packet().unwrap(), Clientbound::Hp(_)));
+
+    // The connection carries on.
+    network.run_until(Duration::from_secs(1), |_| false);
+    assert_eq!(network.disconnect_reason, None);
+    assert_eq!(network.packets.len(), received);
+}
+
+/// Joins a server which answers requests for sudo mode as given.
+fn join_with_sudo(sudo: Sudo) -> Network {
+    let server = FakeServer::with_sudo(
+        Some(Account::new(NAME, PASSWORD)),
+        sudo,
+        game_script(content()),
+    );
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+    network
+}
+
+/// Whether an account's verifier was derived from `password`.
+fn has_password(account: &server::Account, password: &str) -> bool {
+    let server = SrpServer::new(NAME, &account.salt, &account.verifier);
+    let client = SrpClient::new(NAME, password);
+
+    let proof = client
+        .process_challenge(&account.salt, &server.public_ephemeral())
+        .unwrap();
+    server.verify(&client.public_ephemeral(), &proof).is_ok()
+}
+
+#[test]
+fn changes_password_in_sudo_mode() {
+    let mut network = join_with_sudo(Sudo::Verify);
+
+    network.client.change_password(PASSWORD, "hunter3").unwrap();
+    assert!(network.run_until(Duration::from_secs(5), |network| {
+        network.has_event(&Event::PasswordChanged)
+            && network
+                .server
+                .account(NAME)
+                .is_some_and(|account| has_password(&account, "hunter3"))
+    }));
+
+    assert!(!network.has_event(&Event::PasswordChangeDenied));
+}
+
+#[test]
+fn keeps_password_when_old_one_is_wrong() {
+    let mut network = join_with_sudo(Sudo::Verify);
+
+    network
+        .client
+        .change_password("hunter4", "hunter3")
+        .unwrap();
+    assert!(network.run_until(Duration::from_secs(5), |network| {
+        network.has_event(&Event::PasswordChangeDenied)
+    }));
+
+    assert!(!network.has_event(&Event::PasswordChanged));
+    assert!(has_password(
+        &network.server.account(NAME).unwrap(),
+        PASSWORD
+    ));
+
+    // The connection carries on, and another attempt may be made.
+    network.client.change_password(PASSWORD, "hunter3").unwrap();
+    assert!(network.run_until(Duration::from_secs(5), |network| {
+        network.has_event(&Event::PasswordChanged)
+    }));
+}
+
+#[test]
+fn reports_denied_sudo_mode() {
+    let mut network = join_with_sudo(Sudo::Deny);
+
+    network.client.change_password(PASSWORD, "hunter3").unwrap();
+    assert!(network.run_until(Duration::from_secs(5), |network| {
+        network.has_event(&Event::PasswordChangeDenied)
+    }));
+
+    assert!(!network.has_event(&Event::PasswordChanged));
+    assert_eq!(network.run_until_disconnected(Duration::from_secs(1)), None);
+}
+
+#[test]
+fn answers_invalid_sudo_challenge() {
+    let mut network = join_with_sudo(Sudo::InvalidChallenge);
+
+    network.client.change_password(PASSWORD, "hunter3").unwrap();
+
+    // The client refuses to compute a proof, but still answers so the
+    // server isn't left waiting in the middle of the exchange.
+    assert!(network.run_until(Duration::from_secs(5), |network| {
+        network.has_event(&Event::PasswordChangeDenied)
+            && network
+                .server
+                .received
+                .iter()
+                .any(|packet| matches!(packet, Serverbound::SrpBytesM(m) if m.bytes_m.is_empty()))
+    }));
+
+    assert!(has_password(
+        &network.server.account(NAME).unwrap(),
+        PASSWORD
+    ));
+    assert_eq!(network.run_until_disconnected(Duration::from_secs(1)), None);
+}
+
+#[test]
+fn refuses_to_send_overlong_strings() {
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(client(PASSWORD, ClientConfig::default()), server);
+
+    assert!(network.run_until(Duration::from_secs(5), in_game));
+
+    let result = network.client.send_packet(Init2 {
+        lang: "x".repeat(70_000),
+    });
+    assert!(matches!(
+        result,
+        Err(Error::TooLong {
+            what: "string",
+            len: 70_000,
+            ..
+        })
+    ));
+
+    // Nothing was queued, and the connection carries on.
+    assert_eq!(network.run_until_disconnected(Duration::from_secs(1)), None);
+}
+
+#[test]
+fn disconnects_when_name_is_too_long() {
+    let credentials = Credentials {
+        name: "x".repeat(70_000),
+        password: PASSWORD.to_owned(),
+    };
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::new(
+        ClientConnectionState::with_config(credentials, ClientConfig::default()),
+        server,
+    );
+
+    assert!(matches!(
+        network.run_until_disconnected(Duration::from_secs(5)),
+        Some(DisconnectReason::SendFailed(_))
+    ));
+}
+
+#[test]
+fn times_out_when_server_is_silent() {
+    let faults = Faults {
+        loss: 1.0,
+        ..Default::default()
+    };
+    let config = ClientConfig {
+        timeout: Duration::from_secs(2),
+        ..Default::default()
+    };
+    let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+    let mut network = Network::with_faults(client(PASSWORD, config), server, faults, 0);
+
+    let start = network.now;
+
+    assert_eq!(
+        network.run_until_disconnected(Duration::from_secs(5)),
+        Some(DisconnectReason::TimedOut)
+    );
+    assert!(network.now - start >= Duration::from_secs(2));
+}
+
+#[test]
+fn joins_over_unreliable_network() {
+    let faults = Faults {
+        loss: 0.2,
+        duplication: 0.2,
+        reordering: 0.2,
+    };
+
+    for seed in 0..20 {
+        let server = FakeServer::new(Some(Account::new(NAME, PASSWORD)), game_script(content()));
+        let mut network = Network::with_faults(
+            client(PASSWORD, ClientConfig::default()),
+            server,
+            faults,
+            seed,
+        );
+
+        assert!(
+            network.run_until(Duration::from_secs(20), in_game),
+            "seed {seed}: client didn't join, disconnected with {:?}",
+            network.disconnect_reason
+        );
+
+        // Reliable packets arrive exactly once and in order, however the network mangles them.
+        let media: Vec<_> = network
+            .packets
+            .iter()
+            .filter_map(|packet| match packet.packet().unwrap() {
+                Clientbound::Media(media) => Some(media.files[0].name.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            media,
+            ["default_stone.png", "default_dirt.png"],
+            "seed {seed}"
+        );
+
+        let ready_count = network
+            .server
+            .received
+            .iter()
+            .filter(|packet| matches!(packet, Serverbound::ClientReady(_)))
+            .count();
+        assert_eq!(ready_count, 1, "seed {seed}");
+    }
+}
+
+#[test]
+fn reassembles_large_packets() {
+    let node_defs: Vec<u8> = (0..20_000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    let content = GameContent {
+        node_defs: node_defs.clone(),
+        media: (0..100)
+            .map(|i| {
+                (
+                    format!("very_long_texture_name_{i:03}.png"),
+                    vec![i as u8; 10],
+                )
+            })
+            .collect(),
+    };
+
+    for (seed, faults) in [
+        Faults::default(),
+        Faults {
+            loss: 0.1,
+            duplication: 0.1,
+            reordering: 0.3,
+        },
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let server = FakeServer::new(
+            Some(Account::new(NAME, PASSWORD)),
+            game_script(content.clone()),
+        );
+        let mut network = Network::with_faults(
+            client(PASSWORD, ClientConfig::default()),
+            server,
+            faults,
+            seed as u64,
+        );
+
+        assert!(
+            network.run_until(Duration::from_secs(20), in_game),
+            "client didn't join with {faults:?}"
+        );
+
+        let received_node_defs =
+            network
+                .packets
+                .iter()
+                .find_map(|packet| match packet.packet().unwrap() {
+                    Clientbound::NodeDef(node_def) => Some(node_def.data.0),
+                    _ => None,
+                });
+        assert_eq!(received_node_defs.as_ref(), Some(&node_defs));
+
+        // The media request doesn't fit into a frame either.
+        let request = network
+            .server
+            .received
+            .iter()
+            .find(|packet| matches!(packet, Serverbound::RequestMedia(_)))
+            .unwrap();
+        let Serverbound::RequestMedia(files) = request else {
+            unreachable!();
+        };
+        assert_eq!(files.files.len(), 100);
+
+        let mut data = Vec::new();
+        request.serialize(&mut data, &support::CONTEXT).unwrap();
+        assert!(data.len() > MAX_FRAME_SIZE);
+    }
+}
@@ -0,0 +1,427 @@
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+use tiki_proto::clientbound::{
+    AccessDenied, AccessDeniedCode, ActiveObjectMessages, BlockData, Clientbound, Media, MediaFile,
+};
+use tiki_proto::common::{Color, V3f, V3f1000, V3s16};
+use tiki_proto::packet::PacketInfo;
+use tiki_proto::serialize::{
+    Context, Deserialize, Limits, LongBytes, LongList, LongString, RemainingBytes, Serialize,
+    WideString,
+};
+use tiki_proto::serverbound::{Init, Serverbound};
+use tiki_proto::Error;
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut data = Vec::new();
+    value.serialize(&mut data, &Context::default()).unwrap();
+    data
+}
+
+fn decode<T: Serialize>(mut data: &[u8]) -> Result<T, Error> {
+    T::deserialize(&mut data, &Context::default())
+}
+
+/// Decodes a value with every limit set to two.
+fn decode_limited<T: Serialize>(mut data: &[u8]) -> Result<T, Error> {
+    let ctx = Context {
+        limits: Limits {
+            max_string_len: 2,
+            max_list_len: 2,
+            max_bytes_len: 2,
+            max_decompressed_len: 2,
+            max_split_buffer_len: 2,
+        },
+        ..Default::default()
+    };
+
+    T::deserialize(&mut data, &ctx)
+}
+
+/// Checks the encoding of a value, and that it decodes to the same value.
+fn round_trip<T: Serialize + Debug + PartialEq>(value: T, expected: &[u8]) {
+    let data = encode(&value);
+    assert_eq!(data, expected, "encoding {value:?}");
+    assert_eq!(decode::<T>(&data).unwrap(), value);
+}
+
+#[test]
+fn encodes_primitives() {
+    round_trip(true, &[1]);
+    round_trip(false, &[0]);
+    assert!(decode::<bool>(&[2]).unwrap());
+
+    round_trip(LongString("hi".to_owned()), &[0, 0, 0, 2, b'h', b'i']);
+    round_trip(WideString("hé".to_owned()), &[0, 2, 0, b'h', 0, 0xE9]);
+    round_trip(LongList(vec![1u16, 2]), &[0, 0, 0, 2, 0, 1, 0, 2]);
+
+    round_trip(
+        V3s16 {
+            x: 1,
+            y: -1,
+            z: 256,
+        },
+        &[0, 1, 0xFF, 0xFF, 1, 0],
+    );
+    round_trip(
+        Color {
+            a: 0xFF,
+            r: 1,
+            g: 2,
+            b: 3,
+        },
+        &[0xFF, 1, 2, 3],
+    );
+    round_trip(
+        V3f1000(V3f {
+            x: 1.5,
+            y: -2.0,
+            z: 0.0,
+        }),
+        &[0, 0, 0x05, 0xDC, 0xFF, 0xFF, 0xF8, 0x30, 0, 0, 0, 0],
+    );
+}
+
+#[test]
+fn reads_missing_trailing_fields_as_none() {
+    assert_eq!(decode::<Option<u8>>(&[]).unwrap(), None);
+    assert_eq!(decode::<Option<u8>>(&[7]).unwrap(), Some(7));
+    assert_eq!(encode(&None::<u8>), []);
+}
+
+#[test]
+fn rejects_partial_trailing_fields() {
+    assert!(decode::<Option<u16>>(&[7]).is_err());
+    assert!(Option::<u16>::deserialize_borrowed(&mut &[7][..], &Context::default()).is_err());
+}
+
+#[test]
+fn rejects_lengths_over_limits() {
+    fn exceeds<T: Debug>(result: Result<T, Error>, expected: &str) {
+        assert!(
+            matches!(result, Err(Error::LimitExceeded { what, len: 3, limit: 2 }) if what == expected),
+            "{result:?}"
+        );
+    }
+
+    assert_eq!(decode_limited::<String>(&[0, 2, b'h', b'i']).unwrap(), "hi");
+    exceeds(
+        decode_limited::<String>(&[0, 3, b'h', b'i', b'!']),
+        "string",
+    );
+    exceeds(
+        decode_limited::<LongString>(&[0, 0, 0, 3, b'h', b'i', b'!']),
+        "string",
+    );
+    exceeds(
+        decode_limited::<WideString>(&[0, 3, 0, b'h', 0, b'i', 0, b'!']),
+        "wide string",
+    );
+
+    exceeds(decode_limited::<Vec<u8>>(&[0, 3, 1, 2, 3]), "list");
+    exceeds(
+        decode_limited::<LongList<u8>>(&[0, 0, 0, 3, 1, 2, 3]),
+        "list",
+    );
+
+    exceeds(
+        decode_limited::<LongBytes>(&[0, 0, 0, 3, 1, 2, 3]),
+        "binary data",
+    );
+    exceeds(decode_limited::<RemainingBytes>(&[1, 2, 3]), "binary data");
+
+    let ctx = Context {
+        limits: Limits {
+            max_string_len: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    exceeds(
+        Cow::<str>::deserialize_borrowed(&mut &[0, 3, b'h', b'i', b'!'][..], &ctx),
+        "string",
+    );
+}
+
+#[test]
+fn limits_lengths_by_default() {
+    // Any length which fits into a u16 is fine.
+    let len = 40 * 1024u16;
+    let data = [&len.to_be_bytes()[..], &vec![b'a'; len.into()]].concat();
+    assert_eq!(decode::<String>(&data).unwrap().len(), len.into());
+    assert_eq!(decode::<Vec<u8>>(&data).unwrap().len(), len.into());
+
+    let len = u16::MAX;
+    let data = [&len.to_be_bytes()[..], &vec![0; 2 * len as usize]].concat();
+    assert_eq!(decode::<WideString>(&data).unwrap().0.len(), len.into());
+
+    // A u32 length may be longer than a list should be.
+    let len = u16::MAX as u32 + 1;
+    let data = [&len.to_be_bytes()[..], &vec![0; len as usize]].concat();
+    assert!(matches!(
+        decode::<LongList<u8>>(&data),
+        Err(Error::LimitExceeded { what: "list", .. })
+    ));
+
+    // Huge lengths fail before anything is allocated for them.
+    assert!(matches!(
+        decode::<LongBytes>(&[0xFF; 4]),
+        Err(Error::LimitExceeded {
+            what: "binary data",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn replaces_invalid_utf16() {
+    let WideString(s) = decode(&[0, 1, 0xD8, 0x00]).unwrap();
+    assert_eq!(s, "\u{FFFD}");
+}
+
+#[test]
+fn decodes_access_denied_from_old_servers() {
+    let denied: AccessDenied = decode(&[1]).unwrap();
+    assert_eq!(denied.code, AccessDeniedCode::UnexpectedData);
+    assert_eq!(denied.reason, "");
+    assert!(!denied.reconnect);
+
+    let denied: AccessDenied = decode(&[11, 0, 2, b'h', b'i', 1]).unwrap();
+    assert_eq!(denied.code, AccessDeniedCode::Shutdown);
+    assert_eq!(denied.reason, "hi");
+    assert!(denied.reconnect);
+}
+
+#[test]
+fn counts_media_files_with_a_u32() {
+    let media = Media {
+        bunch_count: 1,
+        bunch_index: 0,
+        files: vec![MediaFile {
+            name: "a".to_owned(),
+            data: LongBytes(vec![7]),
+        }],
+    };
+
+    assert_eq!(
+        encode(&media),
+        [0, 1, 0, 0, 0, 0, 0, 1, 0, 1, b'a', 0, 0, 0, 1, 7]
+    );
+}
+
+/// A packet which changed over time, declared outside of tiki-proto.
+#[derive(tiki_macros::Serialize, Debug, PartialEq)]
+struct Versioned {
+    a: u8,
+    #[serialize(since = 40)]
+    b: u16,
+    #[serialize(len = u8)]
+    c: String,
+    #[serialize(with = doubled, trailing)]
+    d: u8,
+}
+
+/// Sends a u8 twice, as an example of a custom encoding.
+mod doubled {
+    use std::io::{Read, Write};
+
+    use tiki_proto::serialize::{Context, Serialize};
+    use tiki_proto::Error;
+
+    pub fn serialize<W: Write>(value: &u8, w: &mut W, ctx: &Context) -> Result<(), Error> {
+        [*value; 2].serialize(w, ctx)
+    }
+
+    pub fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<u8, Error> {
+        let [value, _] = <[u8; 2]>::deserialize(r, ctx)?;
+        Ok(value)
+    }
+}
+
+#[test]
+fn applies_field_attributes() {
+    let value = Versioned {
+        a: 1,
+        b: 2,
+        c: "x".to_owned(),
+        d: 3,
+    };
+
+    let new = Context {
+        protocol_version: 40,
+        ..Default::default()
+    };
+    let mut data = Vec::new();
+    value.serialize(&mut data, &new).unwrap();
+    assert_eq!(data, [1, 0, 2, 1, b'x', 3, 3]);
+    assert_eq!(Versioned::deserialize(&mut &data[..], &new).unwrap(), value);
+
+    let old = Context {
+        protocol_version: 39,
+        ..Default::default()
+    };
+    let mut data = Vec::new();
+    value.serialize(&mut data, &old).unwrap();
+    assert_eq!(data, [1, 1, b'x', 3, 3]);
+
+    // Old peers don't send `b`, and may leave out `d`.
+    let decoded = Versioned::deserialize(&mut &data[..3], &old).unwrap();
+    assert_eq!(
+        decoded,
+        Versioned {
+            b: 0,
+            d: 0,
+            ..value
+        }
+    );
+
+    // But `d` can't be cut off.
+    assert!(Versioned::deserialize(&mut &data[..4], &old).is_err());
+}
+
+#[test]
+fn describes_packets() {
+    assert_eq!(
+        Serverbound::info_by_id(0x02),
+        Some(&PacketInfo {
+            id: 0x02,
+            name: "Init",
+            channel: 1,
+            reliable: false,
+        })
+    );
+    assert_eq!(Serverbound::info_by_id(0x01), None);
+
+    let init = Serverbound::Init(Init {
+        client_max_serialization_ver: 0,
+        supp_compr_modes: 0,
+        min_net_proto_version: 0,
+        max_net_proto_version: 0,
+        player_name: String::new(),
+    });
+    assert_eq!(init.name(), "Init");
+    assert!(!init.is_reliable());
+
+    for packets in [Serverbound::PACKETS, Clientbound::PACKETS] {
+        assert!(packets.windows(2).all(|pair| pair[0].id < pair[1].id));
+    }
+}
+
+/// A tagged enum with data, declared outside of tiki-proto.
+#[derive(tiki_macros::Serialize, Debug, PartialEq)]
+#[repr(u8)]
+enum Shape {
+    Point = 1,
+    Circle {
+        radius: u16,
+    } = 4,
+    Line(u8, #[serialize(len = u8)] String),
+    #[serialize(unknown)]
+    Other(u8),
+}
+
+#[derive(tiki_macros::Serialize, Debug, PartialEq)]
+#[tag(u16)]
+enum Kind {
+    First,
+    Second,
+}
+
+/// The same tag type, given as a string.
+#[derive(tiki_macros::Serialize, Debug, PartialEq)]
+#[tag = "u16"]
+enum OtherKind {
+    First,
+    #[serialize(unknown)]
+    Other(u16),
+}
+
+/// An unknown variant in the middle, which the next variant's tag follows.
+#[derive(tiki_macros::Serialize, Debug, PartialEq)]
+#[repr(u8)]
+enum Level {
+    Low,
+    #[serialize(unknown)]
+    Other(u8),
+    High,
+}
+
+#[test]
+fn encodes_tagged_enums() {
+    round_trip(Shape::Point, &[1]);
+    round_trip(Shape::Circle { radius: 2 }, &[4, 0, 2]);
+    round_trip(Shape::Line(3, "ab".to_owned()), &[5, 3, 2, b'a', b'b']);
+    round_trip(Shape::Other(9), &[9]);
+
+    round_trip(Kind::Second, &[0, 1]);
+    assert!(matches!(
+        decode::<Kind>(&[0, 2]),
+        Err(Error::UnknownTag { ty: "Kind", tag: 2 })
+    ));
+
+    round_trip(OtherKind::First, &[0, 0]);
+    round_trip(OtherKind::Other(300), &[1, 44]);
+
+    // The tag is the same as the discriminant Rust gives the variant.
+    // SAFETY: a `repr(u8)` enum starts with its discriminant as a u8.
+    let discriminant = unsafe { *(&Level::High as *const Level as *const u8) };
+    assert_eq!(discriminant, 2);
+    round_trip(Level::High, &[2]);
+    round_trip(Level::Other(1), &[1]);
+
+    round_trip(AccessDeniedCode::Crash, &[12]);
+    round_trip(AccessDeniedCode::Unknown(200), &[200]);
+}
+
+/// A packet with borrowed fields, declared outside of tiki-proto.
+#[derive(tiki_macros::Serialize, Debug, PartialEq)]
+struct Borrowing<'a> {
+    name: Cow<'a, str>,
+    #[serialize(len = u32)]
+    data: Cow<'a, [u8]>,
+}
+
+#[test]
+fn borrows_from_packets() {
+    let data = [0, 2, b'h', b'i', 0, 0, 0, 1, 7];
+
+    let borrowed = Borrowing::deserialize_borrowed(&mut &data[..], &Context::default()).unwrap();
+    assert!(matches!(borrowed.name, Cow::Borrowed("hi")));
+    assert!(matches!(borrowed.data, Cow::Borrowed([7])));
+
+    // Reading with `Serialize` gives the same, but owned.
+    let owned: Borrowing = decode(&data).unwrap();
+    assert!(matches!(owned.name, Cow::Owned(_)));
+    assert_eq!(owned, borrowed);
+    assert_eq!(encode(&borrowed), data);
+}
+
+#[test]
+fn borrows_block_data() {
+    let data = [0x00, 0x20, 0, 1, 0, 2, 0, 3, 0xAB, 0xCD];
+
+    let Clientbound::BlockData(BlockData { pos, data: block }) =
+        Clientbound::deserialize_borrowed(&mut &data[..], &Context::default()).unwrap()
+    else {
+        panic!("expected block data");
+    };
+    assert_eq!(pos, V3s16 { x: 1, y: 2, z: 3 });
+    assert_eq!(block.0.as_ptr(), data[8..].as_ptr());
+}
+
+#[test]
+fn reads_active_object_messages_lazily() {
+    let ctx = Context::default();
+    let messages = ActiveObjectMessages {
+        data: RemainingBytes(Cow::Borrowed(&[0, 1, 0, 1, 9, 0, 2, 0, 5])),
+    };
+
+    let mut iter = messages.messages(&ctx);
+    let first = iter.next().unwrap().unwrap();
+    assert_eq!((first.id, &first.data[..]), (1, &[9][..]));
+
+    // The second message is cut off, which ends the messages.
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+}
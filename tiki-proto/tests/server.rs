@@ -0,0 +1,286 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tiki_proto::clientbound::AccessDeniedCode;
+use tiki_proto::serialize::{Context, Serialize};
+use tiki_proto::server::{
+    Account, ServerConfig, ServerConnectionState, ServerEvent, ServerInput, ServerOutput,
+};
+use tiki_proto::serverbound::Serverbound;
+use tiki_proto::srp;
+use tiki_proto::transport::{Frame, FrameType, Reliability};
+use tiki_proto::{
+    AuthError, ClientConfig, ClientConnectionState, Credentials, DisconnectReason, Input, Output,
+};
+
+const STEP: Duration = Duration::from_millis(10);
+
+/// Clients talking to a server over a perfect network.
+struct Harness {
+    server: ServerConnectionState,
+    clients: Vec<(SocketAddr, ClientConnectionState)>,
+    now: Instant,
+
+    events: Vec<ServerEvent>,
+    received: Vec<(u16, Serverbound)>,
+}
+
+impl Harness {
+    /// Creates a server which knows the account `alice`, with password `hunter2`.
+    fn new() -> Self {
+        Self::with_config(ServerConfig::default())
+    }
+
+    fn with_config(config: ServerConfig) -> Self {
+        let salt = srp::generate_salt().to_vec();
+        let verifier = srp::generate_verifier("alice", "hunter2", &salt);
+        let account = Account { salt, verifier };
+
+        Self {
+            server: ServerConnectionState::with_config(
+                move |name| (name == "alice").then(|| account.clone()),
+                config,
+            ),
+            clients: Vec::new(),
+            now: Instant::now(),
+
+            events: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    fn connect(&mut self, name: &str, password: &str) -> SocketAddr {
+        let address = SocketAddr::from(([127, 0, 0, 1], 30000 + self.clients.len() as u16));
+
+        let credentials = Credentials {
+            name: name.to_owned(),
+            password: password.to_owned(),
+        };
+        let config = ClientConfig {
+            register: true,
+            ..Default::default()
+        };
+
+        self.clients.push((
+            address,
+            ClientConnectionState::with_config(credentials, config),
+        ));
+
+        address
+    }
+
+    fn client(&mut self, address: SocketAddr) -> &mut ClientConnectionState {
+        let (_, client) = self
+            .clients
+            .iter_mut()
+            .find(|(a, _)| *a == address)
+            .unwrap();
+        client
+    }
+
+    /// Returns the peer ID the server assigned to a logged in client.
+    fn peer_id(&self, name: &str) -> Option<u16> {
+        self.events.iter().find_map(|event| match event {
+            ServerEvent::Authenticated { peer_id, name: n } if n == name => Some(*peer_id),
+            _ => None,
+        })
+    }
+
+    fn step(&mut self) {
+        for (address, client) in &mut self.clients {
+            while let Output::SendData(data) = client.poll_output(self.now) {
+                let input = ServerInput::ReceivedData {
+                    from: *address,
+                    data: &data,
+                };
+                self.server.submit_input(input, self.now).unwrap();
+            }
+        }
+
+        while let ServerOutput::SendData { to, data } = self.server.poll_output(self.now) {
+            if let Some((_, client)) = self.clients.iter_mut().find(|(a, _)| *a == to) {
+                client
+                    .submit_input(Input::ReceivedData(&data), self.now)
+                    .unwrap();
+            }
+        }
+
+        self.events
+            .extend(std::iter::from_fn(|| self.server.poll_event()));
+        self.received.extend(self.server.recv_packets());
+
+        self.now += STEP;
+
+        for (_, client) in &mut self.clients {
+            if client.next_timeout().is_some_and(|t| self.now >= t) {
+                client.submit_input(Input::TimedOut, self.now).unwrap();
+            }
+        }
+
+        if self.server.next_timeout().is_some_and(|t| self.now >= t) {
+            self.server
+                .submit_input(ServerInput::TimedOut, self.now)
+                .unwrap();
+        }
+    }
+
+    fn run_until(&mut self, mut done: impl FnMut(&Self) -> bool) -> bool {
+        for _ in 0..500 {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+
+        done(self)
+    }
+}
+
+/// Whether the server has handed out a client's `Init2`, which it sends once logged in.
+fn sent_init2(harness: &Harness, name: &str) -> bool {
+    let Some(peer_id) = harness.peer_id(name) else {
+        return false;
+    };
+
+    harness
+        .received
+        .iter()
+        .any(|(from, packet)| *from == peer_id && matches!(packet, Serverbound::Init2(_)))
+}
+
+#[test]
+fn logs_in_several_clients() {
+    let mut harness = Harness::new();
+    harness.connect("alice", "hunter2");
+    harness.connect("bob", "swordfish");
+
+    assert!(harness.run_until(|h| sent_init2(h, "alice") && sent_init2(h, "bob")));
+
+    let alice = harness.peer_id("alice").unwrap();
+    let bob = harness.peer_id("bob").unwrap();
+    assert_ne!(alice, bob);
+
+    // Only bob needed an account.
+    let registered: Vec<_> = harness
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            ServerEvent::Registered { peer_id, name, .. } => Some((*peer_id, name.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(registered, [(bob, "bob")]);
+}
+
+#[test]
+fn denies_wrong_password() {
+    let mut harness = Harness::new();
+    let address = harness.connect("alice", "hunter3");
+
+    assert!(harness.run_until(|h| h.clients[0].1.disconnect_reason().is_some()));
+
+    assert_eq!(
+        harness.client(address).disconnect_reason(),
+        Some(&DisconnectReason::AuthFailed(AuthError::WrongPassword))
+    );
+    assert!(harness.events.iter().any(|event| matches!(
+        event,
+        ServerEvent::Disconnected {
+            reason: DisconnectReason::AuthFailed(AuthError::WrongPassword),
+            ..
+        }
+    )));
+}
+
+#[test]
+fn offers_no_sudo_mode_by_default() {
+    let mut harness = Harness::new();
+    let address = harness.connect("alice", "hunter2");
+
+    assert!(harness.run_until(|h| sent_init2(h, "alice")));
+
+    assert_eq!(
+        harness
+            .client(address)
+            .change_password("hunter2", "hunter3"),
+        Err(AuthError::NoSupportedMechanism)
+    );
+}
+
+#[test]
+fn kicks_clients_sending_malformed_packets() {
+    let mut harness = Harness::new();
+    let alice_address = harness.connect("alice", "hunter2");
+    harness.connect("bob", "swordfish");
+
+    assert!(harness.run_until(|h| sent_init2(h, "alice") && sent_init2(h, "bob")));
+
+    let alice = harness.peer_id("alice").unwrap();
+    let bob = harness.peer_id("bob").unwrap();
+
+    // A packet with an ID that doesn't exist.
+    let frame = Frame {
+        peer_id: alice,
+        channel: 0,
+        reliability: Reliability::Unreliable,
+        ty: FrameType::Original,
+    };
+    let mut data = Vec::new();
+    frame.serialize(&mut data, &Context::default()).unwrap();
+    data.extend_from_slice(&[0xff, 0xff]);
+
+    let input = ServerInput::ReceivedData {
+        from: alice_address,
+        data: &data,
+    };
+    harness.server.submit_input(input, harness.now).unwrap();
+
+    let kicked = DisconnectReason::AccessDenied {
+        code: AccessDeniedCode::UnexpectedData,
+        message: String::new(),
+    };
+
+    assert!(harness.run_until(|h| h.clients[0].1.disconnect_reason().is_some()));
+    assert_eq!(
+        harness.client(alice_address).disconnect_reason(),
+        Some(&kicked)
+    );
+    assert!(harness.events.contains(&ServerEvent::Disconnected {
+        peer_id: alice,
+        reason: kicked,
+    }));
+
+    // The other client carries on.
+    harness.run_until(|_| false);
+    assert!(harness.clients[1].1.disconnect_reason().is_none());
+    assert!(!harness.events.iter().any(
+        |event| matches!(event, ServerEvent::Disconnected { peer_id, .. } if *peer_id == bob)
+    ));
+}
+
+#[test]
+fn ignores_clients_while_full() {
+    let mut harness = Harness::with_config(ServerConfig {
+        max_clients: 1,
+        ..Default::default()
+    });
+    harness.connect("alice", "hunter2");
+    harness.connect("bob", "swordfish");
+
+    assert!(harness.run_until(|h| sent_init2(h, "alice")));
+    harness.run_until(|_| false);
+
+    let connected = |h: &Harness| {
+        h.events
+            .iter()
+            .filter(|event| matches!(event, ServerEvent::Connected { .. }))
+            .count()
+    };
+    assert_eq!(connected(&harness), 1);
+    assert!(harness.peer_id("bob").is_none());
+
+    // Once there's room again, the other client gets in.
+    let alice = harness.peer_id("alice").unwrap();
+    harness.server.disconnect(alice, harness.now);
+    assert!(harness.run_until(|h| sent_init2(h, "bob")));
+}
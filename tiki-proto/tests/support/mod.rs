@@ -0,0 +1,570 @@
+//! In-process fake server for testing [`ClientConnectionState`] end to end.
+//!
+//! [`FakeServer`] is a [`ServerConnectionState`], which logs the client in
+//! the way any server built on this crate would. What it answers once the
+//! client is logged in is up to a script, which is called for every packet
+//! the client sends. [`Network`] moves datagrams between the two on a
+//! simulated clock, and can lose, reorder and duplicate them along the way.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use tiki_proto::clientbound::{
+    AcceptSudoMode, AccessDenied, AnnounceMedia, AnnouncedMedia, Clientbound, DenySudoMode,
+    ItemDef, Media, MediaFile, NodeDef, SrpBytesSB,
+};
+use tiki_proto::common::AuthMechs;
+use tiki_proto::packet::ReceivedPacket;
+use tiki_proto::serialize::{Context, Limits, LongBytes};
+use tiki_proto::server::{
+    self, ServerConfig, ServerConnectionState, ServerEvent, ServerInput, ServerOutput,
+};
+use tiki_proto::serverbound::Serverbound;
+use tiki_proto::srp::{self, SrpServer};
+use tiki_proto::{ClientConnectionState, DisconnectReason, Event, Input, Output};
+
+/// Address the client sends from, as far as the server knows.
+pub const CLIENT_ADDRESS: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 30001);
+
+/// Versions the server picks, the newest ones the client supports.
+pub const CONTEXT: Context = Context {
+    protocol_version: tiki_proto::MAX_PROTOCOL_VERSION,
+    serialization_version: tiki_proto::MAX_SERIALIZATION_VERSION,
+    limits: Limits::DEFAULT,
+};
+
+/// Decides what to answer to a packet from the logged in client.
+pub type Script = Box<dyn FnMut(&Serverbound) -> Vec<Clientbound<'static>>>;
+
+/// An account the server knows before the client connects.
+pub struct Account {
+    pub name: String,
+    pub salt: Vec<u8>,
+    pub verifier: Vec<u8>,
+}
+
+impl Account {
+    pub fn new(name: &str, password: &str) -> Self {
+        let salt = srp::generate_salt().to_vec();
+        let verifier = srp::generate_verifier(name, password, &salt);
+
+        Self {
+            name: name.to_owned(),
+            salt,
+            verifier,
+        }
+    }
+}
+
+/// How the fake server answers a logged in client asking for sudo mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sudo {
+    /// Sudo mode isn't offered, like by [`ServerConnectionState`] on its own.
+    Disabled,
+    /// Sudo mode is entered if the client proves it knows the password.
+    Verify,
+    /// Sudo mode is refused without checking the password.
+    Deny,
+    /// The client is sent a `B` of zero, which it must refuse to answer.
+    InvalidChallenge,
+}
+
+/// Accounts by player name, shared with the server's account lookup.
+type Accounts = Arc<Mutex<HashMap<String, server::Account>>>;
+
+pub struct FakeServer {
+    pub state: ServerConnectionState,
+    script: Script,
+    accounts: Accounts,
+
+    sudo: Sudo,
+    /// The SRP exchange and the client's `A`, while waiting for its sudo mode proof.
+    sudo_exchange: Option<(SrpServer, Vec<u8>)>,
+    in_sudo_mode: bool,
+
+    /// Peer ID of the client, once it's connected.
+    pub peer_id: Option<u16>,
+    /// Name of the client, once it's logged in.
+    pub name: Option<String>,
+    /// Everything the client sent once logged in, in the order it was received.
+    pub received: Vec<Serverbound>,
+    pub events: Vec<ServerEvent>,
+}
+
+impl FakeServer {
+    pub fn new(account: Option<Account>, script: Script) -> Self {
+        Self::with_sudo(account, Sudo::Disabled, script)
+    }
+
+    /// Creates a server which handles sudo mode like a game would.
+    pub fn with_sudo(account: Option<Account>, sudo: Sudo, script: Script) -> Self {
+        let config = ServerConfig {
+            sudo_auth_mechs: if sudo == Sudo::Disabled {
+                AuthMechs::empty()
+            } else {
+                AuthMechs::SRP
+            },
+            ..Default::default()
+        };
+
+        let accounts = Accounts::default();
+
+        if let Some(account) = account {
+            accounts.lock().unwrap().insert(
+                account.name,
+                server::Account {
+                    salt: account.salt,
+                    verifier: account.verifier,
+                },
+            );
+        }
+
+        let lookup = accounts.clone();
+        let state = ServerConnectionState::with_config(
+            move |name| lookup.lock().unwrap().get(name).cloned(),
+            config,
+        );
+
+        Self {
+            state,
+            script,
+            accounts,
+
+            sudo,
+            sudo_exchange: None,
+            in_sudo_mode: false,
+
+            peer_id: None,
+            name: None,
+            received: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Handles a datagram from the client, answering according to the script.
+    ///
+    /// Panics if the server fails to handle it.
+    pub fn receive(&mut self, data: &[u8], now: Instant) {
+        let input = ServerInput::ReceivedData {
+            from: CLIENT_ADDRESS,
+            data,
+        };
+        self.state
+            .submit_input(input, now)
+            .expect("server failed to handle datagram");
+
+        self.handle_events();
+
+        let packets: Vec<_> = self
+            .state
+            .recv_packets()
+            .map(|(_, packet)| packet)
+            .collect();
+
+        for packet in packets {
+            let answers = match self.handle_sudo(&packet) {
+                Some(answers) => answers,
+                None => (self.script)(&packet),
+            };
+
+            for answer in answers {
+                self.send(answer);
+            }
+
+            self.received.push(packet);
+        }
+    }
+
+    /// Answers the packets of a password change, which ServerConnectionState leaves to the game.
+    fn handle_sudo(&mut self, packet: &Serverbound) -> Option<Vec<Clientbound<'static>>> {
+        if self.sudo == Sudo::Disabled {
+            return None;
+        }
+
+        let name = self.name.clone()?;
+
+        match packet {
+            Serverbound::SrpBytesA(bytes_a) => {
+                if self.sudo == Sudo::Deny {
+                    return Some(vec![DenySudoMode {}.into()]);
+                }
+
+                let account = self.account(&name)?;
+                let srp = SrpServer::new(&name, &account.salt, &account.verifier);
+
+                let bytes_b = if self.sudo == Sudo::InvalidChallenge {
+                    Vec::new()
+                } else {
+                    srp.public_ephemeral()
+                };
+
+                self.sudo_exchange = Some((srp, bytes_a.bytes_a.clone()));
+
+                Some(vec![SrpBytesSB {
+                    salt: account.salt,
+                    bytes_b,
+                }
+                .into()])
+            }
+            Serverbound::SrpBytesM(bytes_m) => {
+                let (srp, bytes_a) = self.sudo_exchange.take()?;
+
+                self.in_sudo_mode = srp.verify(&bytes_a, &bytes_m.bytes_m).is_ok();
+
+                Some(vec![if self.in_sudo_mode {
+                    AcceptSudoMode {
+                        sudo_auth_mechs: AuthMechs::SRP,
+                    }
+                    .into()
+                } else {
+                    DenySudoMode {}.into()
+                }])
+            }
+            Serverbound::FirstSrp(first_srp) if self.in_sudo_mode => {
+                self.in_sudo_mode = false;
+
+                let account = server::Account {
+                    salt: first_srp.salt.clone(),
+                    verifier: first_srp.verifier.clone(),
+                };
+                self.accounts.lock().unwrap().insert(name, account);
+
+                Some(Vec::new())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns what the server knows about an account.
+    pub fn account(&self, name: &str) -> Option<server::Account> {
+        self.accounts.lock().unwrap().get(name).cloned()
+    }
+
+    fn handle_events(&mut self) {
+        while let Some(event) = self.state.poll_event() {
+            match &event {
+                ServerEvent::Connected { peer_id } => self.peer_id = Some(*peer_id),
+                ServerEvent::Authenticated { name, .. } => self.name = Some(name.clone()),
+                ServerEvent::Registered {
+                    name,
+                    salt,
+                    verifier,
+                    ..
+                } => {
+                    let account = server::Account {
+                        salt: salt.clone(),
+                        verifier: verifier.clone(),
+                    };
+                    self.accounts.lock().unwrap().insert(name.clone(), account);
+                }
+                _ => {}
+            }
+
+            self.events.push(event);
+        }
+    }
+
+    /// Sends a packet to the client.
+    pub fn send<'a>(&mut self, packet: impl Into<Clientbound<'a>>) {
+        let peer_id = self.peer_id.expect("client isn't connected");
+        self.state.send_packet(peer_id, packet).unwrap();
+    }
+
+    /// Tells the client why it's being disconnected.
+    pub fn kick(&mut self, denied: AccessDenied, now: Instant) {
+        let peer_id = self.peer_id.expect("client isn't connected");
+        self.state.kick(peer_id, denied, now).unwrap();
+        self.handle_events();
+    }
+
+    /// Tells the client the connection is closed.
+    pub fn disconnect(&mut self, now: Instant) {
+        let peer_id = self.peer_id.expect("client isn't connected");
+        self.state.disconnect(peer_id, now);
+        self.handle_events();
+    }
+
+    /// Returns whether the server has let the client in.
+    pub fn is_authenticated(&self) -> bool {
+        self.events
+            .iter()
+            .any(|event| matches!(event, ServerEvent::Authenticated { .. }))
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self
+            .state
+            .next_timeout()
+            .is_some_and(|timeout| now >= timeout)
+        {
+            self.state
+                .submit_input(ServerInput::TimedOut, now)
+                .expect("server failed to handle timeout");
+            self.handle_events();
+        }
+    }
+
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.state.poll_output(now) {
+            ServerOutput::SendData { to, data } => {
+                assert_eq!(to, CLIENT_ADDRESS);
+                Some(data)
+            }
+            ServerOutput::Wait => None,
+        }
+    }
+}
+
+/// How unreliable the simulated network is.
+///
+/// Each field is the probability of it happening to a datagram.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    pub loss: f64,
+    pub duplication: f64,
+    /// Reordered datagrams are held back until after the next ones.
+    pub reordering: f64,
+}
+
+/// A client and a fake server connected by a simulated network.
+pub struct Network {
+    pub client: ClientConnectionState,
+    pub server: FakeServer,
+    pub now: Instant,
+    /// How long datagrams take to arrive, in either direction.
+    pub delay: Duration,
+
+    faults: Faults,
+    rng: StdRng,
+    /// Datagrams on their way, with the time they arrive.
+    to_server: Vec<(Instant, Vec<u8>)>,
+    to_client: Vec<(Instant, Vec<u8>)>,
+    held_back: Vec<(bool, Vec<u8>)>,
+
+    /// Every datagram the server received, in order.
+    pub server_datagrams: Vec<Vec<u8>>,
+    /// Everything the client received, in order.
+    pub packets: Vec<ReceivedPacket>,
+    pub events: Vec<Event>,
+    pub disconnect_reason: Option<DisconnectReason>,
+}
+
+/// How far the simulated clock advances per step.
+const STEP: Duration = Duration::from_millis(10);
+
+impl Network {
+    pub fn new(client: ClientConnectionState, server: FakeServer) -> Self {
+        Self::with_faults(client, server, Faults::default(), 0)
+    }
+
+    /// Creates a network which treats datagrams badly, deterministically for a given seed.
+    pub fn with_faults(
+        client: ClientConnectionState,
+        server: FakeServer,
+        faults: Faults,
+        seed: u64,
+    ) -> Self {
+        Self {
+            client,
+            server,
+            now: Instant::now(),
+            delay: Duration::ZERO,
+
+            faults,
+            rng: StdRng::seed_from_u64(seed),
+            to_server: Vec::new(),
+            to_client: Vec::new(),
+            held_back: Vec::new(),
+
+            server_datagrams: Vec::new(),
+            packets: Vec::new(),
+            events: Vec::new(),
+            disconnect_reason: None,
+        }
+    }
+
+    /// Runs until `done` returns true, the client disconnects or `limit` has passed.
+    ///
+    /// Returns whether `done` returned true.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut(&Self) -> bool) -> bool {
+        let deadline = self.now + limit;
+
+        while self.now < deadline {
+            if done(self) {
+                return true;
+            }
+
+            if self.disconnect_reason.is_some() {
+                return false;
+            }
+
+            self.step();
+        }
+
+        done(self)
+    }
+
+    /// Runs until the client disconnects, returning why.
+    pub fn run_until_disconnected(&mut self, limit: Duration) -> Option<DisconnectReason> {
+        self.run_until(limit, |network| network.disconnect_reason.is_some());
+        self.disconnect_reason.clone()
+    }
+
+    /// Exchanges everything that is ready to be sent, then advances the clock.
+    pub fn step(&mut self) {
+        loop {
+            match self.client.poll_output(self.now) {
+                Output::SendData(data) => self.transmit(true, data),
+                Output::Disconnect(reason) => {
+                    self.disconnect_reason = Some(reason);
+                    break;
+                }
+                Output::Wait => break,
+            }
+        }
+
+        for data in arrived(&mut self.to_server, self.now) {
+            self.server.receive(&data, self.now);
+            self.server_datagrams.push(data);
+        }
+
+        while let Some(data) = self.server.poll_transmit(self.now) {
+            self.transmit(false, data);
+        }
+
+        for data in arrived(&mut self.to_client, self.now) {
+            self.client
+                .submit_input(Input::ReceivedData(&data), self.now)
+                .expect("client failed to handle datagram");
+        }
+
+        self.packets.extend(self.client.recv_packets());
+        self.events
+            .extend(std::iter::from_fn(|| self.client.poll_event()));
+
+        self.now += STEP;
+
+        if self
+            .client
+            .next_timeout()
+            .is_some_and(|timeout| self.now >= timeout)
+        {
+            self.client
+                .submit_input(Input::TimedOut, self.now)
+                .expect("client failed to handle timeout");
+        }
+
+        self.server.handle_timeout(self.now);
+    }
+
+    fn transmit(&mut self, to_server: bool, data: Vec<u8>) {
+        if self.rng.gen_bool(self.faults.loss) {
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.faults.duplication) {
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            if self.rng.gen_bool(self.faults.reordering) {
+                self.held_back.push((to_server, data.clone()));
+            } else {
+                self.deliver(to_server, data.clone());
+
+                // Whatever was held back arrives after this one.
+                for (to_server, data) in std::mem::take(&mut self.held_back) {
+                    self.deliver(to_server, data);
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, to_server: bool, data: Vec<u8>) {
+        let arrival = self.now + self.delay;
+
+        if to_server {
+            self.to_server.push((arrival, data));
+        } else {
+            self.to_client.push((arrival, data));
+        }
+    }
+
+    pub fn has_event(&self, event: &Event) -> bool {
+        self.events.contains(event)
+    }
+}
+
+/// Takes the datagrams which have arrived by `now` off a queue, in the order they were sent.
+fn arrived(queue: &mut Vec<(Instant, Vec<u8>)>, now: Instant) -> Vec<Vec<u8>> {
+    let (arrived, pending) = std::mem::take(queue)
+        .into_iter()
+        .partition(|(arrival, _)| *arrival <= now);
+    *queue = pending;
+
+    arrived.into_iter().map(|(_, data)| data).collect()
+}
+
+/// What [`game_script`] sends to a client joining the game.
+#[derive(Clone, Default)]
+pub struct GameContent {
+    pub node_defs: Vec<u8>,
+    /// Names and contents of media files.
+    pub media: Vec<(String, Vec<u8>)>,
+}
+
+/// Script of a game which sends definitions and media to a joining client.
+pub fn game_script(content: GameContent) -> Script {
+    Box::new(move |packet| match packet {
+        Serverbound::Init2(_) => vec![
+            ItemDef {
+                data: LongBytes(Vec::new()),
+            }
+            .into(),
+            NodeDef {
+                data: LongBytes(content.node_defs.clone()),
+            }
+            .into(),
+            AnnounceMedia {
+                files: content
+                    .media
+                    .iter()
+                    .map(|(name, _)| AnnouncedMedia {
+                        name: name.clone(),
+                        sha1: String::new(),
+                    })
+                    .collect(),
+                remote_servers: String::new(),
+            }
+            .into(),
+        ],
+        Serverbound::RequestMedia(request) => content
+            .media
+            .iter()
+            .filter(|(name, _)| request.files.contains(name))
+            .enumerate()
+            .map(|(i, (name, data))| {
+                Media {
+                    bunch_count: request.files.len() as u16,
+                    bunch_index: i as u16,
+                    files: vec![MediaFile {
+                        name: name.clone(),
+                        data: LongBytes(data.clone()),
+                    }],
+                }
+                .into()
+            })
+            .collect(),
+        _ => Vec::new(),
+    })
+}
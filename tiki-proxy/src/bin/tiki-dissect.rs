@@ -0,0 +1,114 @@
+//! Decodes a capture recorded by `tiki-proxy --capture`.
+//!
+//! Prints a summary of every datagram, followed by statistics over the whole
+//! capture: packet counts by id, bytes per channel and retransmissions.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use clap::Parser;
+
+use tiki_proxy::capture::CaptureReader;
+use tiki_proxy::dissect::Dissector;
+use tiki_proxy::format;
+use tiki_proxy::stats::Stats;
+
+#[derive(Parser)]
+struct Args {
+    /// Capture file to decode.
+    capture: PathBuf,
+
+    /// Print JSON lines instead of text. The statistics are printed to stderr.
+    #[arg(long)]
+    json: bool,
+
+    /// Don't cut off long packet dumps.
+    #[arg(long)]
+    full: bool,
+
+    /// Only print the statistics.
+    #[arg(long, short)]
+    quiet: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let file =
+        File::open(&args.capture).with_context(|| format!("opening {}", args.capture.display()))?;
+    let reader = CaptureReader::new(BufReader::new(file))
+        .with_context(|| format!("reading {}", args.capture.display()))?;
+
+    let mut dissectors: HashMap<u32, Dissector> = HashMap::new();
+    let mut stats = Stats::new();
+
+    let mut stdout = io::stdout().lock();
+
+    for record in reader {
+        let record = record.context("reading record")?;
+        let dissector = dissectors.entry(record.session).or_default();
+
+        let dissected = match dissector.dissect(record.direction, &record.data, record.time) {
+            Ok(dissected) => dissected,
+            Err(e) => {
+                stats.record_malformed();
+
+                if args.quiet {
+                    continue;
+                }
+
+                if args.json {
+                    let mut value = format::json_malformed(
+                        record.direction,
+                        record.data.len(),
+                        record.time,
+                        &e,
+                    );
+                    value["session"] = record.session.into();
+                    writeln!(stdout, "{value}")?;
+                } else {
+                    writeln!(
+                        stdout,
+                        "#{} {:>10.3} {} {:>4}B malformed frame: {e}",
+                        record.session,
+                        record.time.as_secs_f64(),
+                        record.direction,
+                        record.data.len()
+                    )?;
+                }
+
+                continue;
+            }
+        };
+
+        stats.record(&dissected);
+
+        if args.quiet {
+            continue;
+        }
+
+        if args.json {
+            let mut value = format::json(&dissected, record.time, args.full);
+            value["session"] = record.session.into();
+            writeln!(stdout, "{value}")?;
+        } else {
+            let line = format::text(&dissected, record.time, args.full);
+            writeln!(stdout, "#{} {line}", record.session)?;
+        }
+    }
+
+    if args.json {
+        eprintln!("{stats}");
+    } else {
+        if !args.quiet {
+            writeln!(stdout)?;
+        }
+
+        writeln!(stdout, "{stats}")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! File format for recorded sessions.
+//!
+//! A capture starts with [`MAGIC`] and the format version as a big-endian
+//! u16, followed by one record per datagram:
+//!
+//! | field     | type | meaning                                           |
+//! |-----------|------|---------------------------------------------------|
+//! | time      | u64  | microseconds since the capture was started        |
+//! | session   | u32  | which client sent or received the datagram        |
+//! | direction | u8   | 0 if sent to the server, 1 if sent to the client  |
+//! | length    | u16  | size of the datagram                              |
+//! | data      |      | the datagram, starting with the transport header  |
+//!
+//! All integers are big-endian, like everything else in the protocol.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::dissect::Direction;
+
+pub const MAGIC: [u8; 8] = *b"TIKICAP\0";
+pub const VERSION: u16 = 1;
+
+/// A datagram as it was seen by the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: Duration,
+    /// Sessions are numbered in the order the clients first sent something.
+    pub session: u32,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+pub struct CaptureWriter<W> {
+    w: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Starts a capture by writing its header.
+    pub fn new(mut w: W) -> io::Result<Self> {
+        w.write_all(&MAGIC)?;
+        w.write_all(&VERSION.to_be_bytes())?;
+        Ok(Self { w })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let len = u16::try_from(record.data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too large"))?;
+
+        let direction: u8 = match record.direction {
+            Direction::ToServer => 0,
+            Direction::ToClient => 1,
+        };
+
+        self.w
+            .write_all(&(record.time.as_micros() as u64).to_be_bytes())?;
+        self.w.write_all(&record.session.to_be_bytes())?;
+        self.w.write_all(&[direction])?;
+        self.w.write_all(&len.to_be_bytes())?;
+        self.w.write_all(&record.data)?;
+
+        // Captures are usually cut short by killing the proxy, so don't keep anything back.
+        self.w.flush()
+    }
+}
+
+/// Reads the records of a capture, in the order they were written.
+pub struct CaptureReader<R> {
+    r: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Opens a capture, checking its header.
+    pub fn new(mut r: R) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+
+        if magic != MAGIC {
+            return Err(invalid_data("not a capture file"));
+        }
+
+        let mut version = [0; 2];
+        r.read_exact(&mut version)?;
+        let version = u16::from_be_bytes(version);
+
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported capture version {version}"
+            )));
+        }
+
+        Ok(Self { r })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut time = [0; 8];
+        let mut filled = 0;
+
+        while filled < time.len() {
+            match self.r.read(&mut time[filled..]) {
+                Ok(0) => break,
+                Ok(len) => filled += len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // The capture may end after any record, but not within one.
+        if filled == 0 {
+            return Ok(None);
+        }
+
+        if filled < time.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "capture ends within a record",
+            ));
+        }
+
+        let mut header = [0; 7];
+        self.r.read_exact(&mut header)?;
+
+        let session = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let direction = match header[4] {
+            0 => Direction::ToServer,
+            1 => Direction::ToClient,
+            direction => return Err(invalid_data(format!("invalid direction {direction}"))),
+        };
+        let len = u16::from_be_bytes([header[5], header[6]]);
+
+        let mut data = vec![0; len as usize];
+        self.r.read_exact(&mut data)?;
+
+        Ok(Some(Record {
+            time: Duration::from_micros(u64::from_be_bytes(time)),
+            session,
+            direction,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
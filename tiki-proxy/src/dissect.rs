@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Cursor;
+use std::time::Duration;
+
+use tiki_proto::clientbound::Clientbound;
+use tiki_proto::packet::packet_id;
+use tiki_proto::serialize::{Context, Serialize};
+use tiki_proto::serverbound::Serverbound;
+use tiki_proto::transport::{Frame, FrameType, Reliability, SplitHeader, TransportError};
+
+/// How long an incomplete unreliable split packet is kept after its last chunk
+/// was seen, which is how long peers wait for the rest.
+const SPLIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Which way a datagram went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::ToServer => "C->S",
+            Direction::ToClient => "S->C",
+        })
+    }
+}
+
+/// What a datagram contained.
+#[derive(Debug)]
+pub struct Dissected {
+    pub direction: Direction,
+    /// Size of the whole datagram.
+    pub size: usize,
+    pub frame: Frame,
+    /// Whether the same reliable frame was seen before, so it was sent again.
+    ///
+    /// The payload of a retransmission isn't decoded again.
+    pub retransmission: bool,
+    /// The packet the frame carried or completed, if any.
+    pub packet: Option<Packet>,
+}
+
+#[derive(Debug)]
+pub struct Packet {
+    pub id: u16,
+    /// Name of the packet type if it's known, even if the packet is malformed.
+    pub name: Option<&'static str>,
+    pub size: usize,
+    pub decoded: Decoded,
+}
+
+#[derive(Debug)]
+pub enum Decoded {
+    Serverbound(Serverbound),
+    Clientbound(Clientbound<'static>),
+    /// A packet type we don't know (yet).
+    Unknown,
+    Malformed(tiki_proto::Error),
+}
+
+/// A split packet, some of whose chunks may be missing.
+struct IncomingSplit {
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Unreliable split packets are given up on if they aren't completed in time.
+    reliable: bool,
+    updated_at: Duration,
+}
+
+/// Decodes the datagrams of one connection, in the order they were sent.
+///
+/// This keeps track of what a peer would: the negotiated versions, which are
+/// needed to decode some packets, split packets which are only partially
+/// received, and which reliable frames were seen already.
+#[derive(Default)]
+pub struct Dissector {
+    context: Context,
+    /// Split packets, by direction, channel and split sequence number.
+    splits: HashMap<(Direction, u8, u16), IncomingSplit>,
+    /// Sequence numbers of reliable frames seen recently, by direction and channel.
+    seen: HashMap<(Direction, u8), HashSet<u16>>,
+}
+
+impl Dissector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Decodes a datagram seen at `time`, which is measured from any fixed point.
+    ///
+    /// Frames which the receiving peer would reject, like chunks which don't
+    /// fit the split packet they belong to, fail like malformed frames.
+    pub fn dissect(
+        &mut self,
+        direction: Direction,
+        data: &[u8],
+        time: Duration,
+    ) -> Result<Dissected, tiki_proto::Error> {
+        self.remove_timed_out_splits(time);
+
+        let mut r = Cursor::new(data);
+        let frame = Frame::deserialize(&mut r, &Context::default())?;
+        let payload = &data[r.position() as usize..];
+
+        let (reliable, retransmission) = match frame.reliability {
+            Reliability::Reliable { seqnum } => {
+                (true, !self.mark_seen(direction, frame.channel, seqnum))
+            }
+            Reliability::Unreliable => (false, false),
+        };
+
+        let packet = match &frame.ty {
+            _ if retransmission => None,
+            FrameType::Control(_) => None,
+            FrameType::Original => Some(self.decode(direction, payload)),
+            FrameType::Split(header) => {
+                let key = (direction, frame.channel, header.seqnum);
+                self.receive_split(key, header, reliable, payload, time)?
+            }
+        };
+
+        Ok(Dissected {
+            direction,
+            size: data.len(),
+            frame,
+            retransmission,
+            packet,
+        })
+    }
+
+    /// Collects a chunk of a split packet, returning the packet once it's complete.
+    fn receive_split(
+        &mut self,
+        key: (Direction, u8, u16),
+        header: &SplitHeader,
+        reliable: bool,
+        payload: &[u8],
+        time: Duration,
+    ) -> Result<Option<Packet>, tiki_proto::Error> {
+        let invalid = || TransportError::InvalidSplitChunk {
+            chunk_number: header.chunk_number,
+            chunk_count: header.chunk_count,
+        };
+
+        if header.chunk_number >= header.chunk_count {
+            return Err(invalid().into());
+        }
+
+        let split = self.splits.entry(key).or_insert_with(|| IncomingSplit {
+            chunks: vec![None; header.chunk_count as usize],
+            reliable,
+            updated_at: time,
+        });
+
+        // Peers drop chunks which disagree with the ones before about the chunk count.
+        if split.chunks.len() != header.chunk_count as usize {
+            return Err(invalid().into());
+        }
+
+        split.updated_at = time;
+        split.chunks[header.chunk_number as usize].get_or_insert_with(|| payload.to_vec());
+
+        if !split.chunks.iter().all(Option::is_some) {
+            return Ok(None);
+        }
+
+        let split = self.splits.remove(&key).unwrap();
+        let data: Vec<u8> = split.chunks.into_iter().flatten().flatten().collect();
+        Ok(Some(self.decode(key.0, &data)))
+    }
+
+    /// Forgets unreliable split packets which peers would have given up on by `time`.
+    fn remove_timed_out_splits(&mut self, time: Duration) {
+        self.splits
+            .retain(|_, split| split.reliable || time < split.updated_at + SPLIT_TIMEOUT);
+    }
+
+    /// Remembers a reliable frame, returning whether it's new.
+    fn mark_seen(&mut self, direction: Direction, channel: u8, seqnum: u16) -> bool {
+        let seen = self.seen.entry((direction, channel)).or_default();
+
+        // Forget the sequence number furthest away, so it can be reused once
+        // the sequence numbers wrap around.
+        seen.remove(&seqnum.wrapping_add(0x8000));
+        seen.insert(seqnum)
+    }
+
+    fn decode(&mut self, direction: Direction, data: &[u8]) -> Packet {
+        let Some(id) = packet_id(data) else {
+            return Packet {
+                id: 0,
+                name: None,
+                size: data.len(),
+                decoded: Decoded::Malformed(
+                    std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into(),
+                ),
+            };
+        };
+
+        let info = match direction {
+            Direction::ToServer => Serverbound::info_by_id(id),
+            Direction::ToClient => Clientbound::info_by_id(id),
+        };
+
+        let mut r = data;
+        let result = match direction {
+            Direction::ToServer => {
+                Serverbound::deserialize(&mut r, &self.context).map(Decoded::Serverbound)
+            }
+            Direction::ToClient => {
+                Clientbound::deserialize(&mut r, &self.context).map(Decoded::Clientbound)
+            }
+        };
+
+        let decoded = match result {
+            Ok(decoded) => decoded,
+            Err(tiki_proto::Error::UnknownPacket(_)) => Decoded::Unknown,
+            Err(e) => Decoded::Malformed(e),
+        };
+
+        // Everything after the server's Hello is encoded in the versions it picked.
+        if let Decoded::Clientbound(Clientbound::Hello(hello)) = &decoded {
+            self.context.protocol_version = hello.protocol_version;
+            self.context.serialization_version = hello.serialization_version;
+        }
+
+        Packet {
+            id,
+            name: info.map(|info| info.name),
+            size: data.len(),
+            decoded,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tiki_proto::transport::{ControlHeader, FrameType, Reliability};
+
+use crate::dissect::{Decoded, Direction, Dissected, Packet};
+
+/// Packet dumps longer than this are cut off, unless the full dump is asked for.
+const MAX_DUMP_LEN: usize = 200;
+
+/// Formats a datagram as a line of human-readable text.
+pub fn text(dissected: &Dissected, time: Duration, full: bool) -> String {
+    let frame = &dissected.frame;
+
+    let reliability = match frame.reliability {
+        Reliability::Reliable { seqnum } => format!("seq={seqnum}"),
+        Reliability::Unreliable => "unrel".to_owned(),
+    };
+
+    let mut line = format!(
+        "{:>10.3} {} peer={} ch={} {:<9} {:>4}B {}",
+        time.as_secs_f64(),
+        dissected.direction,
+        frame.peer_id,
+        frame.channel,
+        reliability,
+        dissected.size,
+        frame_type(&frame.ty),
+    );
+
+    if dissected.retransmission {
+        line.push_str(" (retransmission)");
+    }
+
+    if let Some(packet) = &dissected.packet {
+        line.push_str(&format!(" => 0x{:02x} {}B ", packet.id, packet.size));
+        line.push_str(&dump(packet, full));
+    }
+
+    line
+}
+
+/// Formats a datagram as a JSON object, meant to be written as one line.
+pub fn json(dissected: &Dissected, time: Duration, full: bool) -> Value {
+    let frame = &dissected.frame;
+
+    let seqnum = match frame.reliability {
+        Reliability::Reliable { seqnum } => Some(seqnum),
+        Reliability::Unreliable => None,
+    };
+
+    let mut value = json!({
+        "time": time.as_secs_f64(),
+        "direction": direction_name(dissected.direction),
+        "size": dissected.size,
+        "peer_id": frame.peer_id,
+        "channel": frame.channel,
+        "seqnum": seqnum,
+        "frame": frame_type(&frame.ty),
+        "retransmission": dissected.retransmission,
+    });
+
+    if let FrameType::Split(header) = &frame.ty {
+        value["split"] = json!({
+            "seqnum": header.seqnum,
+            "chunk_number": header.chunk_number,
+            "chunk_count": header.chunk_count,
+        });
+    }
+
+    if let Some(packet) = &dissected.packet {
+        value["packet"] = json!({
+            "id": packet.id,
+            "name": packet.name,
+            "size": packet.size,
+            "dump": dump(packet, full),
+        });
+    }
+
+    value
+}
+
+/// Formats a datagram which couldn't be dissected as a JSON object, like [`json`].
+pub fn json_malformed(
+    direction: Direction,
+    size: usize,
+    time: Duration,
+    error: &tiki_proto::Error,
+) -> Value {
+    json!({
+        "time": time.as_secs_f64(),
+        "direction": direction_name(direction),
+        "size": size,
+        "error": error.to_string(),
+    })
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::ToServer => "to_server",
+        Direction::ToClient => "to_client",
+    }
+}
+
+fn frame_type(ty: &FrameType) -> String {
+    match ty {
+        FrameType::Control(ControlHeader::Ack { seqnum }) => format!("ack {seqnum}"),
+        FrameType::Control(ControlHeader::SetPeerId { peer_id }) => {
+            format!("set_peer_id {peer_id}")
+        }
+        FrameType::Control(ControlHeader::Ping) => "ping".to_owned(),
+        FrameType::Control(ControlHeader::Disco) => "disco".to_owned(),
+        FrameType::Original => "original".to_owned(),
+        FrameType::Split(header) => format!(
+            "split {}/{} of {}",
+            header.chunk_number + 1,
+            header.chunk_count,
+            header.seqnum
+        ),
+    }
+}
+
+fn dump(packet: &Packet, full: bool) -> String {
+    let dump = match &packet.decoded {
+        Decoded::Serverbound(packet) => format!("{packet:?}"),
+        Decoded::Clientbound(packet) => format!("{packet:?}"),
+        Decoded::Unknown => "unknown packet".to_owned(),
+        Decoded::Malformed(e) => format!("malformed packet: {e}"),
+    };
+
+    if full || dump.len() <= MAX_DUMP_LEN {
+        return dump;
+    }
+
+    let mut truncated: String = dump.chars().take(MAX_DUMP_LEN).collect();
+    truncated.push_str("...");
+    truncated
+}
@@ -0,0 +1,6 @@
+//! Tools for looking at the traffic between a client and a server.
+
+pub mod capture;
+pub mod dissect;
+pub mod format;
+pub mod stats;
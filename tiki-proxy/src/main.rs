@@ -0,0 +1,249 @@
+//! UDP proxy which logs the traffic between clients and a server.
+//!
+//! Point the client at the proxy's address instead of the server's. Every
+//! client address gets its own socket towards the server, so several clients
+//! can go through the proxy at once.
+//!
+//! With `--capture`, the traffic is also recorded to a file, which can be
+//! decoded later with `tiki-dissect`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use clap::Parser;
+
+use tiki_proxy::capture::{CaptureWriter, Record};
+use tiki_proxy::dissect::{Direction, Dissector};
+use tiki_proxy::format;
+
+const RECV_BUFFER_SIZE: usize = 1536;
+
+/// Sessions are dropped once the server hasn't sent anything for this long.
+///
+/// Servers ping connected clients every few seconds, so only dead sessions are dropped.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Parser)]
+struct Args {
+    /// Address to accept clients on.
+    #[arg(long, default_value = "127.0.0.1:30001")]
+    listen: SocketAddr,
+
+    /// Address of the server to forward to.
+    #[arg(long)]
+    server: String,
+
+    /// Log JSON lines instead of text.
+    #[arg(long)]
+    json: bool,
+
+    /// Don't cut off long packet dumps.
+    #[arg(long)]
+    full: bool,
+
+    /// Also record the traffic to this file.
+    #[arg(long)]
+    capture: Option<PathBuf>,
+}
+
+/// Writes the decoded traffic of all sessions to stdout, and to the capture file if any.
+struct Logger {
+    args: Args,
+    started_at: Instant,
+    capture: Option<Mutex<CaptureWriter<BufWriter<File>>>>,
+}
+
+impl Logger {
+    fn log(
+        &self,
+        client: SocketAddr,
+        session: u32,
+        dissector: &mut Dissector,
+        direction: Direction,
+        data: &[u8],
+    ) {
+        let time = self.started_at.elapsed();
+
+        if let Some(capture) = &self.capture {
+            let record = Record {
+                time,
+                session,
+                direction,
+                data: data.to_vec(),
+            };
+
+            if let Err(e) = capture.lock().unwrap().write(&record) {
+                eprintln!("failed to write capture: {e}");
+            }
+        }
+
+        let mut stdout = io::stdout().lock();
+
+        let result = match dissector.dissect(direction, data, time) {
+            Ok(dissected) if self.args.json => {
+                let mut value = format::json(&dissected, time, self.args.full);
+                value["client"] = client.to_string().into();
+                writeln!(stdout, "{value}")
+            }
+            Ok(dissected) => {
+                let line = format::text(&dissected, time, self.args.full);
+                writeln!(stdout, "{client} {line}")
+            }
+            Err(e) if self.args.json => {
+                let mut value = format::json_malformed(direction, data.len(), time, &e);
+                value["client"] = client.to_string().into();
+                writeln!(stdout, "{value}")
+            }
+            Err(e) => writeln!(
+                stdout,
+                "{client} {:>10.3} {direction} {:>4}B malformed frame: {e}",
+                time.as_secs_f64(),
+                data.len()
+            ),
+        };
+
+        // Keep forwarding even if nobody reads the log anymore.
+        let _ = result;
+    }
+}
+
+/// A client going through the proxy.
+struct Session {
+    id: u32,
+    upstream: UdpSocket,
+    dissector: Arc<Mutex<Dissector>>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let server = args.server.clone();
+    let listen = Arc::new(
+        UdpSocket::bind(args.listen).with_context(|| format!("binding to {}", args.listen))?,
+    );
+
+    let capture = match &args.capture {
+        Some(path) => {
+            let file =
+                File::create(path).with_context(|| format!("creating {}", path.display()))?;
+            Some(Mutex::new(CaptureWriter::new(BufWriter::new(file))?))
+        }
+        None => None,
+    };
+
+    eprintln!("forwarding {} to {server}", args.listen);
+
+    let logger = Arc::new(Logger {
+        args,
+        started_at: Instant::now(),
+        capture,
+    });
+    let sessions: Arc<Mutex<HashMap<SocketAddr, Session>>> = Arc::default();
+    let mut next_session_id = 0;
+
+    let mut buf = [0; RECV_BUFFER_SIZE];
+
+    loop {
+        let (len, client) = listen.recv_from(&mut buf)?;
+        let data = &buf[..len];
+
+        let mut sessions_guard = sessions.lock().unwrap();
+
+        let session = match sessions_guard.get(&client) {
+            Some(session) => session,
+            None => {
+                let upstream = UdpSocket::bind("0.0.0.0:0")?;
+                upstream
+                    .connect(&server)
+                    .with_context(|| format!("connecting to {server}"))?;
+                upstream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+
+                let session = Session {
+                    id: next_session_id,
+                    upstream,
+                    dissector: Arc::default(),
+                };
+
+                next_session_id += 1;
+
+                eprintln!("{client} connected as session {}", session.id);
+
+                let id = session.id;
+                let upstream = session.upstream.try_clone()?;
+                let dissector = session.dissector.clone();
+                let listen = listen.clone();
+                let logger = logger.clone();
+                let sessions = sessions.clone();
+
+                thread::spawn(move || {
+                    forward_to_client(client, id, &upstream, &listen, &dissector, &logger);
+                    sessions.lock().unwrap().remove(&client);
+                    eprintln!("{client} timed out");
+                });
+
+                sessions_guard.entry(client).or_insert(session)
+            }
+        };
+
+        logger.log(
+            client,
+            session.id,
+            &mut session.dissector.lock().unwrap(),
+            Direction::ToServer,
+            data,
+        );
+
+        if let Err(e) = session.upstream.send(data) {
+            eprintln!("{client} failed to forward to server: {e}");
+        }
+    }
+}
+
+/// Forwards what the server sends until the session times out.
+fn forward_to_client(
+    client: SocketAddr,
+    session: u32,
+    upstream: &UdpSocket,
+    listen: &UdpSocket,
+    dissector: &Mutex<Dissector>,
+    logger: &Logger,
+) {
+    let mut buf = [0; RECV_BUFFER_SIZE];
+
+    loop {
+        let len = match upstream.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return;
+            }
+            // E.g. the server's port is closed, which doesn't end the session.
+            Err(_) => continue,
+        };
+
+        let data = &buf[..len];
+
+        logger.log(
+            client,
+            session,
+            &mut dissector.lock().unwrap(),
+            Direction::ToClient,
+            data,
+        );
+
+        if let Err(e) = listen.send_to(data, client) {
+            eprintln!("{client} failed to forward to client: {e}");
+        }
+    }
+}
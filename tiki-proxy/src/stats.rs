@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use tiki_proto::transport::CHANNEL_COUNT;
+
+use crate::dissect::{Decoded, Direction, Dissected};
+
+/// Totals for one kind of packet.
+#[derive(Debug, Clone, Default)]
+pub struct PacketStats {
+    pub name: Option<&'static str>,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Totals for one direction of a channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelStats {
+    pub datagrams: usize,
+    pub bytes: usize,
+    pub retransmissions: usize,
+}
+
+/// Statistics over all dissected datagrams.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub packets: BTreeMap<(Direction, u16), PacketStats>,
+    pub channels: BTreeMap<(Direction, u8), ChannelStats>,
+    pub malformed_frames: usize,
+    pub malformed_packets: usize,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, dissected: &Dissected) {
+        let channel = self
+            .channels
+            .entry((dissected.direction, dissected.frame.channel))
+            .or_default();
+
+        channel.datagrams += 1;
+        channel.bytes += dissected.size;
+
+        if dissected.retransmission {
+            channel.retransmissions += 1;
+        }
+
+        let Some(packet) = &dissected.packet else {
+            return;
+        };
+
+        if matches!(packet.decoded, Decoded::Malformed(_)) {
+            self.malformed_packets += 1;
+        }
+
+        let stats = self
+            .packets
+            .entry((dissected.direction, packet.id))
+            .or_default();
+
+        stats.count += 1;
+        stats.bytes += packet.size;
+
+        stats.name = packet.name;
+    }
+
+    /// Counts a datagram which couldn't be dissected at all.
+    pub fn record_malformed(&mut self) {
+        self.malformed_frames += 1;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "packets:")?;
+        writeln!(
+            f,
+            "  dir    id  {:<24} {:>8} {:>10}",
+            "name", "count", "bytes"
+        )?;
+
+        for ((direction, id), stats) in &self.packets {
+            writeln!(
+                f,
+                "  {direction} 0x{id:02x}  {:<24} {:>8} {:>10}",
+                stats.name.unwrap_or("(unknown)"),
+                stats.count,
+                stats.bytes
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "channels:")?;
+        writeln!(
+            f,
+            "  dir  ch {:>10} {:>10} {:>16}",
+            "datagrams", "bytes", "retransmissions"
+        )?;
+
+        for direction in [Direction::ToServer, Direction::ToClient] {
+            for channel in 0..CHANNEL_COUNT as u8 {
+                let stats = self
+                    .channels
+                    .get(&(direction, channel))
+                    .copied()
+                    .unwrap_or_default();
+
+                writeln!(
+                    f,
+                    "  {direction} {channel:>2} {:>10} {:>10} {:>16}",
+                    stats.datagrams, stats.bytes, stats.retransmissions
+                )?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "malformed frames: {}", self.malformed_frames)?;
+        write!(f, "malformed packets: {}", self.malformed_packets)
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::time::Duration;
+
+use tiki_proxy::capture::{CaptureReader, CaptureWriter, Record, MAGIC};
+use tiki_proxy::dissect::Direction;
+
+fn records() -> Vec<Record> {
+    vec![
+        Record {
+            time: Duration::from_micros(1),
+            session: 0,
+            direction: Direction::ToServer,
+            data: vec![1, 2, 3],
+        },
+        Record {
+            time: Duration::from_secs(90),
+            session: 1,
+            direction: Direction::ToClient,
+            data: Vec::new(),
+        },
+    ]
+}
+
+fn write(records: &[Record]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    let mut writer = CaptureWriter::new(&mut data).unwrap();
+    for record in records {
+        writer.write(record).unwrap();
+    }
+
+    data
+}
+
+fn read(data: &[u8]) -> io::Result<Vec<Record>> {
+    CaptureReader::new(data)?.collect()
+}
+
+#[test]
+fn reads_what_was_written() {
+    let records = records();
+    let data = write(&records);
+
+    assert!(data.starts_with(&MAGIC));
+    assert_eq!(read(&data).unwrap(), records);
+    assert_eq!(read(&write(&[])).unwrap(), []);
+}
+
+#[test]
+fn rejects_records_which_are_cut_off() {
+    let records = records();
+    let data = write(&records);
+
+    // The last record is empty, so cutting off one byte cuts off its header.
+    let error = read(&data[..data.len() - 1]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn rejects_records_cut_off_within_the_time() {
+    let records = records();
+    let first = write(&records[..1]);
+    let data = write(&records);
+
+    for len in 1..8 {
+        let error = read(&data[..first.len() + len]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{len} bytes");
+    }
+}
+
+#[test]
+fn rejects_other_files() {
+    let mut data = write(&records());
+    data[0] = b'X';
+    assert_eq!(read(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    let mut data = write(&records());
+    data[MAGIC.len() + 1] += 1;
+    assert_eq!(read(&data).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn refuses_datagrams_too_large_to_record() {
+    let oversized = Record {
+        time: Duration::ZERO,
+        session: 0,
+        direction: Direction::ToServer,
+        data: vec![0; 0x10000],
+    };
+    let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+    assert!(writer.write(&oversized).is_err());
+}
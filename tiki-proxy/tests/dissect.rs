@@ -0,0 +1,213 @@
+mod support;
+
+use std::fs::File;
+use std::process::Command;
+use std::time::Duration;
+
+use tiki_proto::serverbound::Serverbound;
+use tiki_proto::transport::{ControlHeader, FrameType, Reliability};
+use tiki_proxy::capture::{CaptureWriter, Record};
+use tiki_proxy::dissect::{Decoded, Direction, Dissected, Dissector};
+
+use support::{chunk, datagram, init, original};
+
+const RELIABLE: Reliability = Reliability::Reliable { seqnum: 65500 };
+const UNRELIABLE: Reliability = Reliability::Unreliable;
+
+fn dissect(dissector: &mut Dissector, data: &[u8], secs: u64) -> Dissected {
+    dissector
+        .dissect(Direction::ToServer, data, Duration::from_secs(secs))
+        .unwrap()
+}
+
+/// Returns the name of the player in a dissected `Init`, if it completed one.
+fn player_name(dissected: &Dissected) -> Option<&str> {
+    match &dissected.packet.as_ref()?.decoded {
+        Decoded::Serverbound(Serverbound::Init(init)) => Some(&init.player_name),
+        decoded => panic!("unexpected packet {decoded:?}"),
+    }
+}
+
+#[test]
+fn decodes_packets() {
+    let mut dissector = Dissector::new();
+
+    let dissected = dissect(&mut dissector, &original(RELIABLE, &init("alice")), 0);
+    assert_eq!(dissected.direction, Direction::ToServer);
+    assert_eq!(player_name(&dissected), Some("alice"));
+
+    let packet = dissected.packet.unwrap();
+    assert_eq!((packet.id, packet.name), (0x02, Some("Init")));
+    assert_eq!(packet.size, init("alice").len());
+
+    let ping = datagram(RELIABLE, FrameType::Control(ControlHeader::Ping), &[]);
+    assert!(dissect(&mut dissector, &ping, 0).packet.is_none());
+
+    // The data ends in the middle of the name.
+    let data = init("alice");
+    let dissected = dissect(&mut dissector, &original(UNRELIABLE, &data[..9]), 0);
+    assert!(matches!(
+        dissected.packet.unwrap().decoded,
+        Decoded::Malformed(_)
+    ));
+}
+
+#[test]
+fn reassembles_split_packets() {
+    let data = init("alice");
+    let (first, rest) = data.split_at(3);
+    let (second, third) = rest.split_at(4);
+
+    for reliable in [false, true] {
+        let mut dissector = Dissector::new();
+
+        // Reliable chunks have their own sequence numbers, which are checked for retransmissions.
+        let with_seqnum = |seqnum: u16| match reliable {
+            true => Reliability::Reliable { seqnum },
+            false => UNRELIABLE,
+        };
+
+        let third = dissect(&mut dissector, &chunk(with_seqnum(3), 2, 3, third), 0);
+        assert_eq!(player_name(&third), None);
+
+        let first = dissect(&mut dissector, &chunk(with_seqnum(1), 0, 3, first), 0);
+        assert_eq!(player_name(&first), None);
+
+        // Chunks seen twice are only kept once.
+        let again = dissect(&mut dissector, &chunk(with_seqnum(4), 0, 3, b"???"), 0);
+        assert_eq!(player_name(&again), None);
+
+        let second = dissect(&mut dissector, &chunk(with_seqnum(2), 1, 3, second), 0);
+        assert_eq!(player_name(&second), Some("alice"));
+    }
+}
+
+#[test]
+fn forgets_incomplete_unreliable_splits() {
+    let data = init("alice");
+    let (first, second) = data.split_at(3);
+
+    let mut dissector = Dissector::new();
+
+    dissect(&mut dissector, &chunk(UNRELIABLE, 0, 2, first), 0);
+    // Peers wait for the rest as long as chunks keep coming.
+    dissect(&mut dissector, &chunk(UNRELIABLE, 0, 2, first), 20);
+    let done = dissect(&mut dissector, &chunk(UNRELIABLE, 1, 2, second), 40);
+    assert_eq!(player_name(&done), Some("alice"));
+
+    dissect(&mut dissector, &chunk(UNRELIABLE, 0, 2, first), 50);
+    let late = dissect(&mut dissector, &chunk(UNRELIABLE, 1, 2, second), 80);
+    assert_eq!(player_name(&late), None);
+
+    // Reliable chunks always arrive eventually.
+    let mut dissector = Dissector::new();
+    dissect(&mut dissector, &chunk(RELIABLE, 0, 2, first), 100);
+    let late = dissect(
+        &mut dissector,
+        &chunk(Reliability::Reliable { seqnum: 65501 }, 1, 2, second),
+        1000,
+    );
+    assert_eq!(player_name(&late), Some("alice"));
+}
+
+#[test]
+fn rejects_chunks_which_dont_fit() {
+    let data = init("alice");
+    let (first, second) = data.split_at(3);
+
+    let mut dissector = Dissector::new();
+    let result = |dissector: &mut Dissector, data: &[u8]| {
+        dissector.dissect(Direction::ToServer, data, Duration::ZERO)
+    };
+
+    assert!(result(&mut dissector, &chunk(UNRELIABLE, 2, 2, second)).is_err());
+
+    dissect(&mut dissector, &chunk(UNRELIABLE, 0, 2, first), 0);
+    assert!(result(&mut dissector, &chunk(UNRELIABLE, 1, 3, second)).is_err());
+
+    // The split packet is still there.
+    let done = dissect(&mut dissector, &chunk(UNRELIABLE, 1, 2, second), 0);
+    assert_eq!(player_name(&done), Some("alice"));
+}
+
+#[test]
+fn detects_retransmissions() {
+    let mut dissector = Dissector::new();
+    let data = original(RELIABLE, &init("alice"));
+
+    let dissected = dissect(&mut dissector, &data, 0);
+    assert!(!dissected.retransmission);
+    assert!(dissected.packet.is_some());
+
+    // Sent again because the ack got lost, so it isn't decoded again.
+    let dissected = dissect(&mut dissector, &data, 1);
+    assert!(dissected.retransmission);
+    assert!(dissected.packet.is_none());
+
+    // Each direction and channel counts on its own.
+    let other_direction = dissector
+        .dissect(Direction::ToClient, &data, Duration::from_secs(1))
+        .unwrap();
+    assert!(!other_direction.retransmission);
+
+    let unreliable = original(UNRELIABLE, &init("alice"));
+    assert!(!dissect(&mut dissector, &unreliable, 1).retransmission);
+    assert!(!dissect(&mut dissector, &unreliable, 1).retransmission);
+
+    // Once the sequence numbers have gone half way around, they may be reused.
+    let halfway = original(
+        Reliability::Reliable {
+            seqnum: 65500u16.wrapping_add(0x8000),
+        },
+        &[0, 0],
+    );
+    assert!(!dissect(&mut dissector, &halfway, 2).retransmission);
+    assert!(!dissect(&mut dissector, &data, 3).retransmission);
+}
+
+#[test]
+fn prints_malformed_frames_as_json() {
+    let mut records = vec![
+        Record {
+            time: Duration::ZERO,
+            session: 0,
+            direction: Direction::ToServer,
+            data: original(RELIABLE, &init("alice")),
+        },
+        // Cut off within the frame header.
+        Record {
+            time: Duration::from_secs(1),
+            session: 0,
+            direction: Direction::ToServer,
+            data: original(RELIABLE, &[])[..5].to_vec(),
+        },
+    ];
+    records.push(records[0].clone());
+
+    let path = std::env::temp_dir().join(format!("tiki-dissect-{}.cap", std::process::id()));
+    let mut writer = CaptureWriter::new(File::create(&path).unwrap()).unwrap();
+    for record in &records {
+        writer.write(record).unwrap();
+    }
+    drop(writer);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_tiki-dissect"))
+        .arg("--json")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[1]["session"], 0);
+    assert_eq!(lines[1]["direction"], "to_server");
+    assert_eq!(lines[1]["size"], 5);
+    assert!(lines[1]["error"].is_string());
+}
@@ -0,0 +1,46 @@
+mod support;
+
+use std::time::Duration;
+
+use tiki_proto::transport::Reliability;
+use tiki_proxy::dissect::{Direction, Dissector};
+use tiki_proxy::stats::Stats;
+
+use support::{init, original, CHANNEL};
+
+#[test]
+fn adds_up_datagrams_and_packets() {
+    let mut dissector = Dissector::new();
+    let mut stats = Stats::new();
+
+    let reliable = original(Reliability::Reliable { seqnum: 1 }, &init("alice"));
+    let truncated = original(Reliability::Unreliable, &init("alice")[..9]);
+
+    for data in [&reliable, &reliable, &truncated] {
+        let dissected = dissector
+            .dissect(Direction::ToServer, data, Duration::ZERO)
+            .unwrap();
+        stats.record(&dissected);
+    }
+    stats.record_malformed();
+
+    let channel = stats.channels[&(Direction::ToServer, CHANNEL)];
+    assert_eq!(channel.datagrams, 3);
+    assert_eq!(channel.bytes, 2 * reliable.len() + truncated.len());
+    assert_eq!(channel.retransmissions, 1);
+    assert_eq!(stats.channels.len(), 1);
+
+    // The retransmission isn't counted as another packet.
+    let packets = &stats.packets[&(Direction::ToServer, 0x02)];
+    assert_eq!(packets.name, Some("Init"));
+    assert_eq!(packets.count, 2);
+    assert_eq!(packets.bytes, init("alice").len() + 9);
+    assert_eq!(stats.packets.len(), 1);
+
+    assert_eq!(stats.malformed_packets, 1);
+    assert_eq!(stats.malformed_frames, 1);
+
+    let text = stats.to_string();
+    assert!(text.contains("malformed frames: 1"), "{text}");
+    assert!(text.contains("Init"), "{text}");
+}
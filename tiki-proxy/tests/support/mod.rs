@@ -0,0 +1,54 @@
+//! Datagrams for feeding a [`Dissector`](tiki_proxy::dissect::Dissector).
+
+#![allow(dead_code)]
+
+use tiki_proto::serialize::{Context, Serialize};
+use tiki_proto::serverbound::{Init, Serverbound};
+use tiki_proto::transport::{Frame, FrameType, Reliability, SplitHeader};
+
+/// Channel the client sends `Init` on.
+pub const CHANNEL: u8 = 1;
+
+/// A serialized `Init` for the player `name`.
+pub fn init(name: &str) -> Vec<u8> {
+    let packet = Serverbound::Init(Init {
+        client_max_serialization_ver: 29,
+        supp_compr_modes: 0,
+        min_net_proto_version: 37,
+        max_net_proto_version: 46,
+        player_name: name.to_owned(),
+    });
+
+    let mut data = Vec::new();
+    packet.serialize(&mut data, &Context::default()).unwrap();
+    data
+}
+
+pub fn datagram(reliability: Reliability, ty: FrameType, payload: &[u8]) -> Vec<u8> {
+    let frame = Frame {
+        peer_id: 2,
+        channel: CHANNEL,
+        reliability,
+        ty,
+    };
+
+    let mut data = Vec::new();
+    frame.serialize(&mut data, &Context::default()).unwrap();
+    data.extend_from_slice(payload);
+    data
+}
+
+pub fn original(reliability: Reliability, payload: &[u8]) -> Vec<u8> {
+    datagram(reliability, FrameType::Original, payload)
+}
+
+/// One chunk of the split packet with sequence number 7.
+pub fn chunk(reliability: Reliability, number: u16, count: u16, payload: &[u8]) -> Vec<u8> {
+    let header = SplitHeader {
+        seqnum: 7,
+        chunk_count: count,
+        chunk_number: number,
+    };
+
+    datagram(reliability, FrameType::Split(header), payload)
+}
@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
 use std::path::Path;
 
-use tiki_proto::serialize::Serialize;
+use tiki_proto::clientbound::BlockData;
+use tiki_proto::serialize::{check_limit, deserialize_trailing, Context, LongBytes, Serialize};
 
 use crate::postgres::PostgresBackend;
 
@@ -38,83 +39,165 @@ pub struct Node {
     pub param2: u8,
 }
 
+/// Extra data of a node, like the text on a sign or the items in a chest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeMetadata {
+    /// Values by name, which may be any bytes.
+    pub fields: HashMap<String, Vec<u8>>,
+    /// Names of the fields which servers keep to themselves, and don't send to clients.
+    pub private: HashSet<String>,
+    /// The node's inventory in its text format, without the final `EndInventory` line.
+    pub inventory: String,
+}
+
+/// A block, either loaded from a world or received from a server.
+///
+/// Blocks from servers use the server's global node IDs, so they have no
+/// name-ID mapping of their own.
 pub struct Block {
     flags: u8,
     lighting_complete: u16,
     timestamp: u32,
     block_data: Vec<u8>,
+    metadata: HashMap<u16, NodeMetadata>,
     id_to_name: HashMap<NodeId, String>,
     name_to_id: HashMap<String, NodeId>,
 }
 
-impl Block {
-    pub fn get_node(&self, pos: Pos) -> Node {
-        assert!(pos.x >= 0 && pos.y >= 0 && pos.z >= 0);
+/// Number of nodes along each edge of a block.
+const BLOCK_SIZE: usize = 16;
+const NODE_COUNT: usize = BLOCK_SIZE * BLOCK_SIZE * BLOCK_SIZE;
+
+/// Bytes per node for its content ID, and for both params, in serialized blocks.
+const CONTENT_WIDTH: u8 = 2;
+const PARAMS_WIDTH: u8 = 2;
+
+/// Timestamp of blocks which don't have one, like those sent by servers.
+const TIMESTAMP_UNDEFINED: u32 = u32::MAX;
+
+/// Block flags, as in the first byte of a serialized block.
+const FLAG_UNDERGROUND: u8 = 0x01;
+const FLAG_DAY_NIGHT_DIFFERS: u8 = 0x02;
+const FLAG_NOT_GENERATED: u8 = 0x08;
 
-        let index = 16 * 16 * pos.z as usize + 16 * pos.y as usize + pos.x as usize;
+/// Returns the index of a node within a block, or `None` if the position is outside it.
+fn node_index(pos: Pos) -> Option<usize> {
+    let in_block = |c: i32| (0..BLOCK_SIZE as i32).contains(&c);
+
+    if !(in_block(pos.x) && in_block(pos.y) && in_block(pos.z)) {
+        return None;
+    }
+
+    Some(BLOCK_SIZE * BLOCK_SIZE * pos.z as usize + BLOCK_SIZE * pos.y as usize + pos.x as usize)
+}
+
+impl Block {
+    /// Returns the node at a position within the block, or `None` if the position is outside it.
+    pub fn get_node(&self, pos: Pos) -> Option<Node> {
+        let index = node_index(pos)?;
 
         let id_hi = self.block_data[2 * index] as u16;
         let id_lo = self.block_data[2 * index + 1] as u16;
 
-        Node {
+        Some(Node {
             id: (id_hi << 8) | id_lo,
-            param1: self.block_data[2 * 16 * 16 * 16 + index],
-            param2: self.block_data[3 * 16 * 16 * 16 + index],
-        }
+            param1: self.block_data[2 * NODE_COUNT + index],
+            param2: self.block_data[3 * NODE_COUNT + index],
+        })
+    }
+
+    /// Returns the name of a node ID, if the block's mapping has it.
+    pub fn name(&self, id: u16) -> Option<&str> {
+        self.id_to_name.get(&id).map(String::as_str)
+    }
+
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.name_to_id.get(name).copied()
+    }
+
+    /// Returns the metadata of the node at a position within the block, if it has any.
+    pub fn metadata(&self, pos: Pos) -> Option<&NodeMetadata> {
+        self.metadata.get(&(node_index(pos)? as u16))
+    }
+
+    /// Returns whether the block is below the surface, so sunlight doesn't reach it.
+    pub fn is_underground(&self) -> bool {
+        self.flags & FLAG_UNDERGROUND != 0
+    }
+
+    /// Returns whether the block looks different at night than during the day.
+    pub fn day_night_differs(&self) -> bool {
+        self.flags & FLAG_DAY_NIGHT_DIFFERS != 0
+    }
+
+    /// Returns whether the map generator is done with the block.
+    pub fn is_generated(&self) -> bool {
+        self.flags & FLAG_NOT_GENERATED == 0
     }
 
-    pub fn name(&self, id: u16) -> &str {
-        self.id_to_name.get(&id).unwrap()
+    /// Returns which sides of the block have their light computed, with one
+    /// bit per direction for the day and one for the night.
+    pub fn lighting_complete(&self) -> u16 {
+        self.lighting_complete
     }
 
-    pub fn id(&self, name: &str) -> u16 {
-        *self.name_to_id.get(name).unwrap()
+    /// Returns when the block was last saved, in seconds since the world was created.
+    ///
+    /// Blocks sent by servers have no timestamp.
+    pub fn timestamp(&self) -> Option<u32> {
+        (self.timestamp != TIMESTAMP_UNDEFINED).then_some(self.timestamp)
     }
 }
 
 impl Serialize for Block {
-    fn serialize<W: Write>(&self, w: &mut W) {
+    fn serialize<W: Write>(&self, _w: &mut W, _ctx: &Context) -> Result<(), tiki_proto::Error> {
         todo!()
     }
 
-    fn deserialize<R: Read>(r: &mut R) -> Result<Self, tiki_proto::Error> {
-        let version = u8::deserialize(r)?;
+    fn deserialize<R: Read>(r: &mut R, ctx: &Context) -> Result<Self, tiki_proto::Error> {
+        let version = u8::deserialize(r, ctx)?;
 
         if version < 29 {
             return Self::deserialize_before_v29(r);
         }
 
-        let r = &mut zstd::Decoder::new(r)?;
+        let data = decompress(zstd::Decoder::new(r)?, ctx)?;
+        let r = &mut data.as_slice();
 
-        let flags = u8::deserialize(r)?;
-        let lighting_complete = u16::deserialize(r)?;
+        let flags = u8::deserialize(r, ctx)?;
+        let lighting_complete = u16::deserialize(r, ctx)?;
 
-        let timestamp = u32::deserialize(r)?;
-        let _mapping_version = u8::deserialize(r)?;
+        let timestamp = u32::deserialize(r, ctx)?;
+        let _mapping_version = u8::deserialize(r, ctx)?;
 
         let mut id_to_name = HashMap::new();
         let mut name_to_id = HashMap::new();
 
-        let name_id_mapping_count = u16::deserialize(r)?;
+        let name_id_mapping_count = u16::deserialize(r, ctx)?;
+        check_limit(
+            "name-ID mapping",
+            name_id_mapping_count as usize,
+            ctx.limits.max_list_len,
+        )?;
+
         for _ in 0..name_id_mapping_count {
-            let id = u16::deserialize(r)?;
-            let name = String::deserialize(r)?;
+            let id = u16::deserialize(r, ctx)?;
+            let name = String::deserialize(r, ctx)?;
 
             id_to_name.insert(id, name.clone());
             name_to_id.insert(name, id);
         }
 
-        let _content_width = u8::deserialize(r);
-        let _params_width = u8::deserialize(r);
-
-        let mut block_data = vec![0; 4 * 16 * 16 * 16];
-        r.read_exact(&mut block_data)?;
+        read_widths(r, ctx)?;
+        let block_data = read_nodes(r)?;
+        let metadata = read_metadata(r, ctx)?;
 
         Ok(Self {
             flags,
             lighting_complete,
             timestamp,
             block_data,
+            metadata,
             id_to_name,
             name_to_id,
         })
@@ -122,9 +205,209 @@ impl Serialize for Block {
 }
 
 impl Block {
-    fn deserialize_before_v29<R: Read>(r: &mut R) -> Result<Block, tiki_proto::Error> {
+    fn deserialize_before_v29<R: Read>(_r: &mut R) -> Result<Block, tiki_proto::Error> {
         unimplemented!()
     }
+
+    /// Reads a block in the format servers send it in, which differs from the
+    /// one in worlds.
+    ///
+    /// The format depends on `ctx.serialization_version`, rather than a version
+    /// in the data. From version 29 on, the whole block is compressed with zstd.
+    /// Before, the nodes and their metadata are compressed separately with zlib.
+    pub fn deserialize_network(r: &mut &[u8], ctx: &Context) -> Result<Self, tiki_proto::Error> {
+        if ctx.serialization_version >= 29 {
+            // The block is followed by more data, so only its own frame may be read.
+            let data = decompress(zstd::Decoder::with_buffer(&mut *r)?.single_frame(), ctx)?;
+            let data = &mut data.as_slice();
+
+            let flags = u8::deserialize(data, ctx)?;
+            let lighting_complete = u16::deserialize(data, ctx)?;
+            read_widths(data, ctx)?;
+            let block_data = read_nodes(data)?;
+            let metadata = read_metadata(data, ctx)?;
+
+            Ok(Self::from_network(
+                flags,
+                lighting_complete,
+                block_data,
+                metadata,
+            ))
+        } else {
+            let flags = u8::deserialize(r, ctx)?;
+            let lighting_complete = u16::deserialize(r, ctx)?;
+            read_widths(r, ctx)?;
+
+            let nodes = decompress(flate2::bufread::ZlibDecoder::new(&mut *r), ctx)?;
+            let block_data = read_nodes(&mut nodes.as_slice())?;
+
+            let metadata = decompress(flate2::bufread::ZlibDecoder::new(&mut *r), ctx)?;
+            let metadata = read_metadata(&mut metadata.as_slice(), ctx)?;
+
+            Ok(Self::from_network(
+                flags,
+                lighting_complete,
+                block_data,
+                metadata,
+            ))
+        }
+    }
+
+    fn from_network(
+        flags: u8,
+        lighting_complete: u16,
+        block_data: Vec<u8>,
+        metadata: HashMap<u16, NodeMetadata>,
+    ) -> Self {
+        Self {
+            flags,
+            lighting_complete,
+            timestamp: TIMESTAMP_UNDEFINED,
+            block_data,
+            metadata,
+            id_to_name: HashMap::new(),
+            name_to_id: HashMap::new(),
+        }
+    }
+}
+
+/// A block sent by a server in a `BlockData` packet.
+pub struct NetworkBlock {
+    /// Position of the block, in blocks.
+    pub pos: Pos,
+    pub block: Block,
+    /// Version of the data following the block which only servers send.
+    ///
+    /// Clients ignore it, and old servers may leave it out.
+    pub network_specific_version: Option<u8>,
+}
+
+impl NetworkBlock {
+    /// Decodes the block in a packet, with the versions negotiated with the server.
+    pub fn decode(packet: &BlockData, ctx: &Context) -> Result<Self, tiki_proto::Error> {
+        let r = &mut &packet.data.0[..];
+
+        let block = Block::deserialize_network(r, ctx)?;
+        let network_specific_version = deserialize_trailing(r, ctx)?;
+
+        Ok(Self {
+            pos: pos(
+                packet.pos.x.into(),
+                packet.pos.y.into(),
+                packet.pos.z.into(),
+            ),
+            block,
+            network_specific_version,
+        })
+    }
+}
+
+/// Decompresses a block, or part of one, so it can't expand without bounds.
+fn decompress(decoder: impl Read, ctx: &Context) -> Result<Vec<u8>, tiki_proto::Error> {
+    let max_len = ctx.limits.max_decompressed_len;
+    let mut data = Vec::new();
+    decoder.take(max_len as u64 + 1).read_to_end(&mut data)?;
+    check_limit("decompressed block", data.len(), max_len)?;
+
+    Ok(data)
+}
+
+fn read_widths<R: Read>(r: &mut R, ctx: &Context) -> Result<(), tiki_proto::Error> {
+    if u8::deserialize(r, ctx)? != CONTENT_WIDTH {
+        return Err(tiki_proto::Error::InvalidValue("content width"));
+    }
+
+    if u8::deserialize(r, ctx)? != PARAMS_WIDTH {
+        return Err(tiki_proto::Error::InvalidValue("params width"));
+    }
+
+    Ok(())
+}
+
+/// Reads the content IDs of all nodes, followed by all their param1s and then param2s.
+fn read_nodes<R: Read>(r: &mut R) -> Result<Vec<u8>, tiki_proto::Error> {
+    let mut block_data = vec![0; 4 * NODE_COUNT];
+    r.read_exact(&mut block_data)?;
+
+    Ok(block_data)
+}
+
+/// Reads the metadata of the nodes which have any, by their index in the block.
+fn read_metadata(
+    r: &mut &[u8],
+    ctx: &Context,
+) -> Result<HashMap<u16, NodeMetadata>, tiki_proto::Error> {
+    let mut metadata = HashMap::new();
+
+    // Version 1 lacks the private flags, and 0 means there's no metadata.
+    let version = u8::deserialize(r, ctx)?;
+
+    if version == 0 {
+        return Ok(metadata);
+    }
+
+    if version > 2 {
+        return Err(tiki_proto::Error::InvalidValue("node metadata version"));
+    }
+
+    let count = u16::deserialize(r, ctx)?;
+    check_limit("node metadata", count as usize, NODE_COUNT)?;
+
+    for _ in 0..count {
+        let index = u16::deserialize(r, ctx)?;
+
+        if index as usize >= NODE_COUNT {
+            return Err(tiki_proto::Error::InvalidValue("node metadata position"));
+        }
+
+        let field_count = u32::deserialize(r, ctx)?;
+        check_limit(
+            "node metadata fields",
+            field_count as usize,
+            ctx.limits.max_list_len,
+        )?;
+
+        let mut node = NodeMetadata::default();
+
+        for _ in 0..field_count {
+            let name = String::deserialize(r, ctx)?;
+            let LongBytes(value) = LongBytes::deserialize(r, ctx)?;
+
+            if version >= 2 && bool::deserialize(r, ctx)? {
+                node.private.insert(name.clone());
+            }
+
+            node.fields.insert(name, value);
+        }
+
+        node.inventory = read_inventory(r)?;
+        metadata.insert(index, node);
+    }
+
+    Ok(metadata)
+}
+
+/// Reads the lines of an inventory, up to the one saying `EndInventory`.
+fn read_inventory(r: &mut &[u8]) -> Result<String, tiki_proto::Error> {
+    let mut inventory = String::new();
+
+    loop {
+        let Some(end) = r.iter().position(|&b| b == b'\n') else {
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+        };
+
+        let (line, rest) = r.split_at(end + 1);
+        *r = rest;
+
+        let line = std::str::from_utf8(line)
+            .map_err(|_| tiki_proto::Error::NonUnicodeString(line.to_vec()))?;
+
+        if line.trim() == "EndInventory" {
+            return Ok(inventory);
+        }
+
+        inventory.push_str(line);
+    }
 }
 
 pub trait Backend {
@@ -167,10 +450,18 @@ impl World {
         Ok(Self { meta, backend })
     }
 
+    /// Returns the settings in the world's `world.mt`.
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
     pub fn get_block(&mut self, pos: Pos) -> Result<Block, Error> {
         let data = self.backend.get_block_data(pos)?;
 
-        Ok(Block::deserialize(&mut data.as_slice())?)
+        Ok(Block::deserialize(
+            &mut data.as_slice(),
+            &Context::default(),
+        )?)
     }
 }
 
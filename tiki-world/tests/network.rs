@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+use std::io::Write;
+
+use tiki_proto::clientbound::BlockData;
+use tiki_proto::common::V3s16;
+use tiki_proto::serialize::{Context, Limits, RemainingBytes};
+use tiki_world::{pos, NetworkBlock};
+
+const INVENTORY: &str = "List main 1\nWidth 0\nEmpty\nEndInventoryList\n";
+
+/// Nodes where every content ID is 3, and only the node at (1, 2, 3) has metadata.
+fn nodes_and_metadata() -> (Vec<u8>, Vec<u8>) {
+    let mut nodes = [0, 3].repeat(4096);
+    nodes.extend([0; 2 * 4096]);
+
+    let mut metadata = vec![2, 0, 1];
+    metadata.extend(801u16.to_be_bytes());
+    metadata.extend([0, 0, 0, 1, 0, 4]);
+    metadata.extend(b"text");
+    metadata.extend([0, 0, 0, 2]);
+    metadata.extend(b"hi");
+    metadata.push(1);
+    metadata.extend(INVENTORY.as_bytes());
+    metadata.extend(b"EndInventory\n");
+
+    (nodes, metadata)
+}
+
+fn decode(data: Vec<u8>, serialization_version: u8) -> NetworkBlock {
+    decode_with_limits(data, serialization_version, Limits::default()).unwrap()
+}
+
+fn decode_with_limits(
+    data: Vec<u8>,
+    serialization_version: u8,
+    limits: Limits,
+) -> Result<NetworkBlock, tiki_proto::Error> {
+    let packet = BlockData {
+        pos: V3s16 { x: -1, y: 0, z: 2 },
+        data: RemainingBytes(Cow::Owned(data)),
+    };
+    let ctx = Context {
+        serialization_version,
+        limits,
+        ..Default::default()
+    };
+
+    NetworkBlock::decode(&packet, &ctx)
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Limits which a block with a megabyte of nodes exceeds, though it's small compressed.
+fn bomb_limits() -> Limits {
+    Limits {
+        max_decompressed_len: 64 * 1024,
+        ..Default::default()
+    }
+}
+
+fn is_bomb_error(result: Result<NetworkBlock, tiki_proto::Error>) -> bool {
+    matches!(
+        result,
+        Err(tiki_proto::Error::LimitExceeded {
+            what: "decompressed block",
+            len,
+            limit: 65536,
+        }) if len == 65537
+    )
+}
+
+fn check(block: &NetworkBlock) {
+    assert_eq!(block.pos, pos(-1, 0, 2));
+    assert_eq!(block.network_specific_version, Some(2));
+
+    let node = block.block.get_node(pos(15, 15, 15)).unwrap();
+    assert_eq!((node.id, node.param1, node.param2), (3, 0, 0));
+
+    let metadata = block.block.metadata(pos(1, 2, 3)).unwrap();
+    assert_eq!(metadata.fields["text"], b"hi");
+    assert!(metadata.private.contains("text"));
+    assert_eq!(metadata.inventory, INVENTORY);
+    assert!(block.block.metadata(pos(0, 0, 0)).is_none());
+
+    assert!(block.block.is_underground());
+    assert!(!block.block.day_night_differs());
+    assert!(block.block.is_generated());
+    assert_eq!(block.block.lighting_complete(), 0xFFFF);
+    assert_eq!(block.block.timestamp(), None);
+}
+
+#[test]
+fn decodes_zstd_blocks() {
+    let (nodes, metadata) = nodes_and_metadata();
+    let raw = [&[1, 0xFF, 0xFF, 2, 2][..], &nodes, &metadata].concat();
+
+    let mut data = zstd::encode_all(&raw[..], 0).unwrap();
+    data.push(2);
+
+    check(&decode(data, 29));
+}
+
+#[test]
+fn decodes_zlib_blocks() {
+    let (nodes, metadata) = nodes_and_metadata();
+
+    let data = [
+        &[1, 0xFF, 0xFF, 2, 2][..],
+        &zlib(&nodes),
+        &zlib(&metadata),
+        &[2],
+    ]
+    .concat();
+
+    check(&decode(data, 28));
+}
+
+#[test]
+fn rejects_zstd_bombs() {
+    let raw = [&[0, 0xFF, 0xFF, 2, 2][..], &[0; 1024 * 1024]].concat();
+
+    let mut data = zstd::encode_all(&raw[..], 0).unwrap();
+    data.push(2);
+
+    assert!(is_bomb_error(decode_with_limits(data, 29, bomb_limits())));
+}
+
+#[test]
+fn rejects_zlib_bombs() {
+    let (_, metadata) = nodes_and_metadata();
+
+    let data = [
+        &[0, 0xFF, 0xFF, 2, 2][..],
+        &zlib(&[0; 1024 * 1024]),
+        &zlib(&metadata),
+        &[2],
+    ]
+    .concat();
+
+    assert!(is_bomb_error(decode_with_limits(data, 28, bomb_limits())));
+}
+
+#[test]
+fn has_no_nodes_outside_the_block() {
+    let (nodes, metadata) = nodes_and_metadata();
+    let raw = [&[0, 0xFF, 0xFF, 2, 2][..], &nodes, &metadata].concat();
+
+    let mut data = zstd::encode_all(&raw[..], 0).unwrap();
+    data.push(2);
+    let block = decode(data, 29).block;
+
+    assert!(block.get_node(pos(0, 0, 0)).is_some());
+    for outside in [pos(16, 0, 0), pos(0, 16, 0), pos(0, 0, 16), pos(-1, 0, 0)] {
+        assert!(block.get_node(outside).is_none(), "{outside:?}");
+        assert!(block.metadata(outside).is_none(), "{outside:?}");
+    }
+}